@@ -0,0 +1,384 @@
+use std::borrow::Cow;
+
+use crate::{Addressable, Device, InterruptLines, MemoryAccessError, Tick};
+
+/// How a device attached to the bus sees the addresses of its accesses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressMode {
+    /// The device receives the bus address unchanged
+    Absolute,
+    /// The device is moved to another base, it receives the bus address
+    /// rebased from the bus range to its own address range
+    Relative,
+}
+
+/// A device mapped to a range of the bus
+struct Mapping {
+    /// The first bus address of the device
+    start: usize,
+    /// The bus address following the last byte of the device
+    end: usize,
+    /// The start address of the device in its own address range
+    device_start: usize,
+    mode: AddressMode,
+    device: Box<dyn Device>,
+    /// The interrupt lines driven by the device
+    lines: InterruptLines,
+}
+
+impl Mapping {
+    /// Translates a bus address to the address seen by the device
+    fn translate(&self, address: usize) -> usize {
+        match self.mode {
+            AddressMode::Absolute => address,
+            AddressMode::Relative => address - self.start + self.device_start,
+        }
+    }
+}
+
+/// A system bus routing accesses to the devices owning the addresses
+///
+/// Device ranges never overlap. Accesses may span adjacent devices, every byte
+/// of an access has to be mapped. A write spanning several devices is only
+/// performed if every device accepts its part.
+#[derive(Default)]
+pub struct Bus {
+    /// The mapped devices, sorted by start address
+    mappings: Vec<Mapping>,
+    /// The interrupt lines asserted by any device after the last tick
+    asserted: InterruptLines,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attaches a device at the range given by its start and end addresses
+    pub fn attach(&mut self, device: Box<dyn Device>) -> Result<(), MemoryAccessError> {
+        let (start, end) = (device.start_address(), device.end_address());
+        self.insert(start, end, AddressMode::Absolute, device)
+    }
+
+    /// Attaches a device at `base`
+    ///
+    /// The device sees the addresses of its own range, the bus address `base`
+    /// reaches the start address of the device.
+    /// The device occupies as many bytes as its address range spans.
+    pub fn attach_at(
+        &mut self,
+        base: usize,
+        device: Box<dyn Device>,
+    ) -> Result<(), MemoryAccessError> {
+        let size = device.end_address() - device.start_address();
+        let end = base
+            .checked_add(size)
+            .ok_or(MemoryAccessError::OutOfBounds {
+                address: base,
+                size,
+            })?;
+        self.insert(base, end, AddressMode::Relative, device)
+    }
+
+    fn insert(
+        &mut self,
+        start: usize,
+        end: usize,
+        mode: AddressMode,
+        device: Box<dyn Device>,
+    ) -> Result<(), MemoryAccessError> {
+        let device_start = device.start_address();
+        let position = self.mappings.partition_point(|m| m.start < start);
+        let overlaps_previous = position > 0 && self.mappings[position - 1].end > start;
+        let overlaps_next = self.mappings.get(position).is_some_and(|m| m.start < end);
+        if start >= end || overlaps_previous || overlaps_next {
+            return Err(MemoryAccessError::AddressAlreadyMapped { address: start });
+        }
+
+        self.mappings.insert(
+            position,
+            Mapping {
+                start,
+                end,
+                device_start,
+                mode,
+                device,
+                lines: InterruptLines::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Returns the attached devices in address order
+    pub fn devices(&self) -> impl Iterator<Item = &dyn Device> {
+        self.mappings.iter().map(|m| m.device.as_ref())
+    }
+
+    /// Returns the device mapped at `address`
+    pub fn device_at(&self, address: usize) -> Option<&dyn Device> {
+        self.find(address).ok().map(|m| m.device.as_ref())
+    }
+
+    /// Ticks every device in address order
+    ///
+    /// A line is asserted while any device raises it. Returns the lines that
+    /// became asserted during this tick.
+    pub fn tick(&mut self, tick: Tick) -> InterruptLines {
+        let mut asserted = InterruptLines::new();
+        for mapping in &mut self.mappings {
+            mapping.device.tick(tick, &mut mapping.lines);
+            asserted = asserted.union(&mapping.lines);
+        }
+        let raised = asserted.difference(&self.asserted);
+        self.asserted = asserted;
+        raised
+    }
+
+    /// Returns the interrupt lines asserted after the last tick
+    pub fn asserted_lines(&self) -> &InterruptLines {
+        &self.asserted
+    }
+
+    /// Resets every device and deasserts all interrupt lines
+    pub fn reset(&mut self) {
+        for mapping in &mut self.mappings {
+            mapping.device.reset();
+            mapping.lines = InterruptLines::new();
+        }
+        self.asserted = InterruptLines::new();
+    }
+
+    fn find(&self, address: usize) -> Result<&Mapping, MemoryAccessError> {
+        let position = self.mappings.partition_point(|m| m.start <= address);
+        position
+            .checked_sub(1)
+            .map(|position| &self.mappings[position])
+            .filter(|m| address < m.end)
+            .ok_or(MemoryAccessError::AddressNotMapped)
+    }
+
+    fn find_mut(&mut self, address: usize) -> Result<&mut Mapping, MemoryAccessError> {
+        let position = self.mappings.partition_point(|m| m.start <= address);
+        position
+            .checked_sub(1)
+            .map(|position| &mut self.mappings[position])
+            .filter(|m| address < m.end)
+            .ok_or(MemoryAccessError::AddressNotMapped)
+    }
+
+    /// Splits an access into pieces that each lie within a single device
+    ///
+    /// Returns the index of the mapping, the bus address and the length of
+    /// every piece. Fails if any byte of the access is not mapped.
+    fn route(
+        &self,
+        address: usize,
+        size: usize,
+    ) -> Result<Vec<(usize, usize, usize)>, MemoryAccessError> {
+        let end = address
+            .checked_add(size)
+            .ok_or(MemoryAccessError::OutOfBounds { address, size })?;
+        let mut pieces = Vec::new();
+        let mut current = address;
+        while current < end {
+            let position = self.mappings.partition_point(|m| m.start <= current);
+            let index = position
+                .checked_sub(1)
+                .filter(|index| current < self.mappings[*index].end)
+                .ok_or(MemoryAccessError::AddressNotMapped)?;
+            let len = (end - current).min(self.mappings[index].end - current);
+            pieces.push((index, current, len));
+            current += len;
+        }
+        Ok(pieces)
+    }
+}
+
+impl Addressable for Bus {
+    fn read_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        let mapping = self.find(address)?;
+        mapping.device.read_byte(mapping.translate(address))
+    }
+
+    fn fetch_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        let mapping = self.find(address)?;
+        mapping.device.fetch_byte(mapping.translate(address))
+    }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, MemoryAccessError> {
+        let pieces = self.route(address, size)?;
+        if let [(index, address, size)] = pieces[..] {
+            let mapping = &self.mappings[index];
+            return mapping.device.read_bytes(mapping.translate(address), size);
+        }
+
+        let mut bytes = Vec::with_capacity(size);
+        for (index, address, len) in pieces {
+            let mapping = &self.mappings[index];
+            bytes.extend_from_slice(&mapping.device.read_bytes(mapping.translate(address), len)?);
+        }
+        Ok(Cow::Owned(bytes))
+    }
+
+    fn write_byte(&mut self, address: usize, value: u8) -> Result<(), MemoryAccessError> {
+        let mapping = self.find_mut(address)?;
+        let address = mapping.translate(address);
+        mapping.device.write_byte(address, value)
+    }
+
+    fn write_bytes(&mut self, address: usize, value: &[u8]) -> Result<(), MemoryAccessError> {
+        let pieces = self.route(address, value.len())?;
+        if pieces.len() > 1 {
+            // nothing is written unless every device accepts its part
+            for &(index, address, len) in &pieces {
+                let mapping = &self.mappings[index];
+                mapping
+                    .device
+                    .check_write(mapping.translate(address), len)?;
+            }
+        }
+
+        let mut value = value;
+        for (index, address, len) in pieces {
+            let (chunk, rest) = value.split_at(len);
+            let mapping = &mut self.mappings[index];
+            let address = mapping.translate(address);
+            mapping.device.write_bytes(address, chunk)?;
+            value = rest;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::DRAM;
+
+    #[test]
+    fn test_bus_routing() {
+        let mut bus = Bus::new();
+        let mut low = DRAM::new(0, 0x1000);
+        low.alloc(0, 0x1000).unwrap();
+        bus.attach(Box::new(low)).unwrap();
+
+        // a relative device sees offsets from its base
+        let mut high = DRAM::new(0, 0x1000);
+        high.alloc(0, 0x1000).unwrap();
+        bus.attach_at(0x4000, Box::new(high)).unwrap();
+
+        bus.write_bytes(0xffe, &[1, 2]).unwrap();
+        bus.write_byte(0x4010, 0x42).unwrap();
+        assert_eq!(*bus.read_bytes(0xffe, 2).unwrap(), [1, 2]);
+        assert_eq!(bus.read_byte(0x4010).unwrap(), 0x42);
+        assert_eq!(bus.devices().count(), 2);
+        assert_eq!(bus.device_at(0x4fff).unwrap().name(), "DRAM");
+
+        assert!(matches!(
+            bus.read_byte(0x2000),
+            Err(MemoryAccessError::AddressNotMapped)
+        ));
+        assert!(matches!(
+            bus.read_byte(0x5000),
+            Err(MemoryAccessError::AddressNotMapped)
+        ));
+        assert!(matches!(
+            bus.read_bytes(0xfff, 2),
+            Err(MemoryAccessError::AddressNotMapped)
+        ));
+    }
+
+    #[test]
+    fn test_bus_relative_device_base() {
+        use crate::rom::ROM;
+
+        let mut dram = DRAM::new(0x1000, 0x1000);
+        dram.alloc(0x1000, 0x1000).unwrap();
+        let mut bus = Bus::new();
+        bus.attach_at(0x8000, Box::new(dram)).unwrap();
+        bus.attach_at(0x9000, Box::new(ROM::new(0x100, vec![1, 2, 3, 4])))
+            .unwrap();
+
+        bus.write_bytes(0x8010, &[5, 6]).unwrap();
+        assert_eq!(*bus.read_bytes(0x800f, 4).unwrap(), [0, 5, 6, 0]);
+        assert_eq!(*bus.read_bytes(0x8fff, 3).unwrap(), [0, 1, 2]);
+
+        // a write running into the ROM leaves the DRAM unchanged
+        assert!(matches!(
+            bus.write_bytes(0x8ffe, &[7, 8, 9]),
+            Err(MemoryAccessError::WriteProtected { address: 0x100 })
+        ));
+        assert_eq!(*bus.read_bytes(0x8ffe, 2).unwrap(), [0, 0]);
+    }
+
+    #[test]
+    fn test_bus_spanning_access() {
+        let mut bus = Bus::new();
+        for base in [0, 0x1000] {
+            let mut dram = DRAM::new(0, 0x1000);
+            dram.alloc(0, 0x1000).unwrap();
+            bus.attach_at(base, Box::new(dram)).unwrap();
+        }
+
+        bus.write_bytes(0xffc, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(*bus.read_bytes(0xffc, 8).unwrap(), [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(bus.read_byte(0x1000).unwrap(), 5);
+        assert!(matches!(
+            bus.read_bytes(0x1ffc, 8),
+            Err(MemoryAccessError::AddressNotMapped)
+        ));
+    }
+
+    #[test]
+    fn test_bus_rejects_overlaps() {
+        let mut bus = Bus::new();
+        bus.attach(Box::new(DRAM::new(0x1000, 0x1000))).unwrap();
+        bus.attach(Box::new(DRAM::new(0x3000, 0x1000))).unwrap();
+
+        for base in [0x0800, 0x1800, 0x2800, 0x3fff] {
+            assert!(matches!(
+                bus.attach_at(base, Box::new(DRAM::new(0, 0x1000))),
+                Err(MemoryAccessError::AddressAlreadyMapped { .. })
+            ));
+        }
+        // devices may touch each other
+        bus.attach_at(0x2000, Box::new(DRAM::new(0, 0x1000)))
+            .unwrap();
+        bus.attach(Box::new(DRAM::new(0, 0x1000))).unwrap();
+        assert_eq!(bus.devices().count(), 4);
+    }
+
+    #[test]
+    fn test_bus_interrupt_lines() {
+        use crate::timer::{Timer, CONTROL_ENABLE, CONTROL_INTERRUPT};
+        use crate::uart::Uart;
+
+        // both devices drive line 4
+        let uart = Uart::new(0x3f8).with_interrupt_line(4);
+        let console = uart.console();
+        let mut bus = Bus::new();
+        bus.attach(Box::new(uart)).unwrap();
+        bus.attach_at(0x1000, Box::new(Timer::new(0, 4))).unwrap();
+        bus.write_byte(0x3f9, 1).unwrap();
+        bus.write_byte(0x1010, 2).unwrap();
+        bus.write_byte(0x1000, (CONTROL_ENABLE | CONTROL_INTERRUPT) as u8)
+            .unwrap();
+
+        let tick = Tick {
+            cycles: 1,
+            instructions: 1,
+        };
+        assert_eq!(bus.tick(tick), InterruptLines::new());
+        console.push_input(b"a");
+        assert!(bus.tick(tick).is_raised(4));
+        // the timer expiring keeps the line asserted, it is not raised again
+        assert!(!bus.tick(tick).is_raised(4));
+        bus.read_byte(0x3f8).unwrap();
+        assert!(!bus.tick(tick).is_raised(4));
+        assert_eq!(bus.asserted_lines().raised().collect::<Vec<_>>(), [4]);
+
+        bus.reset();
+        assert_eq!(*bus.asserted_lines(), InterruptLines::new());
+        assert!(bus.tick(tick).raised().next().is_none());
+    }
+}
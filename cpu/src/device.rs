@@ -1,79 +1,108 @@
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
 
-use crate::{Addressable, Device, MemoryAccessError};
+use crate::{AccessType, Addressable, Device, MemoryAccessError};
 
 /// A generic DRAM device
+///
+/// The memory is sparse: only pages that have been allocated are backed by
+/// storage, so the DRAM can span a huge address range.
 #[derive(Debug, Clone)]
 pub struct DRAM {
-    /// The data of the DRAM
-    segments: BTreeMap<usize, Segment>,
+    /// The allocated pages, indexed by page number relative to the base address
+    ///
+    /// Pages are shared with snapshots and copied on the first write.
+    pages: BTreeMap<usize, Arc<Page>>,
     /// The base address of the DRAM
     base_address: usize,
     /// The size of the DRAM
     size: usize,
     /// The alignment of the DRAM, defaults to 1 (byte aligned)
     alignment: usize,
+    /// Access statistics per page, if tracking is enabled
+    tracking: Option<RefCell<BTreeMap<usize, PageTracking>>>,
 }
 
 const BLOCK_SIZE: usize = 1 << 12; // 4 KiB
-const DEFAULT_ALLOC_SIZE: usize = 1 << 12; // 4 KiB
 
+type Page = [u8; BLOCK_SIZE];
+
+/// The access counts and dirty bits of a page
 #[derive(Debug, Clone)]
-struct Segment {
-    start: usize,
-    data: Vec<u8>,
+struct PageTracking {
+    reads: u64,
+    writes: u64,
+    executes: u64,
+    /// One bit per byte of the page, set when the byte is written
+    dirty: [u64; BLOCK_SIZE / 64],
 }
 
-impl Ord for Segment {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.start.cmp(&other.start)
-    }
+/// The number of accesses to a page since tracking was enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageAccesses {
+    /// The address of the first byte of the page
+    pub address: usize,
+    pub reads: u64,
+    pub writes: u64,
+    pub executes: u64,
 }
 
-impl Eq for Segment {}
-
-impl PartialOrd for Segment {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.start.partial_cmp(&other.start)
-    }
-}
-
-impl PartialEq for Segment {
-    fn eq(&self, other: &Self) -> bool {
-        self.start == other.start && self.data == other.data
+impl PageAccesses {
+    /// Returns the number of accesses of the given type
+    pub fn count(&self, access: AccessType) -> u64 {
+        match access {
+            AccessType::Read => self.reads,
+            AccessType::Write => self.writes,
+            AccessType::Execute => self.executes,
+        }
     }
 }
 
 impl Addressable for DRAM {
     fn read_byte(&self, address: usize) -> Result<u8, crate::MemoryAccessError> {
         self.check_access(address, 1)?;
-        for segment in self.segments.values() {
-            if segment.contains(address) {
-                return segment.read_byte(address);
-            }
-        }
-
-        Err(crate::MemoryAccessError::AddressNotMapped)
+        let (page, offset) = self.locate(address);
+        let value = self.page(page)?[offset];
+        self.track(AccessType::Read, address, 1);
+        Ok(value)
     }
 
-    fn read_bytes(&self, address: usize, size: usize) -> Result<&[u8], crate::MemoryAccessError> {
+    fn fetch_byte(&self, address: usize) -> Result<u8, crate::MemoryAccessError> {
         self.check_access(address, 1)?;
-        for segment in self.segments.values() {
-            if segment.contains(address) {
-                return segment.read_bytes(address, size);
+        let (page, offset) = self.locate(address);
+        let value = self.page(page)?[offset];
+        self.track(AccessType::Execute, address, 1);
+        Ok(value)
+    }
+
+    fn read_bytes(
+        &self,
+        address: usize,
+        size: usize,
+    ) -> Result<Cow<'_, [u8]>, crate::MemoryAccessError> {
+        self.check_access(address, size)?;
+        let (page, offset) = self.locate(address);
+        let bytes = if size <= BLOCK_SIZE - offset {
+            Cow::Borrowed(&self.page(page)?[offset..offset + size])
+        } else {
+            let mut bytes = Vec::with_capacity(size);
+            for (page, offset, len) in self.chunks(address, size) {
+                bytes.extend_from_slice(&self.page(page)?[offset..offset + len]);
             }
-        }
-        Err(crate::MemoryAccessError::AddressNotMapped)
+            Cow::Owned(bytes)
+        };
+        self.track(AccessType::Read, address, size);
+        Ok(bytes)
     }
 
     fn write_byte(&mut self, address: usize, value: u8) -> Result<(), crate::MemoryAccessError> {
         self.check_access(address, 1)?;
-        for segment in self.segments.values_mut() {
-            if segment.contains(address) {
-                return segment.write_byte(address, value);
-            }
-        }
-        Err(crate::MemoryAccessError::AddressNotMapped)
+        let (page, offset) = self.locate(address);
+        self.page_mut(page)?[offset] = value;
+        self.track(AccessType::Write, address, 1);
+        Ok(())
     }
 
     fn write_bytes(
@@ -81,13 +110,17 @@ impl Addressable for DRAM {
         address: usize,
         value: &[u8],
     ) -> Result<(), crate::MemoryAccessError> {
-        self.check_access(address, 1)?;
-        for segment in self.segments.values_mut() {
-            if segment.contains(address) {
-                return segment.write_bytes(address, value);
-            }
+        // nothing is written unless the whole range is mapped
+        self.check_write(address, value.len())?;
+
+        let mut rest = value;
+        for (page, offset, len) in self.chunks(address, value.len()) {
+            let (chunk, tail) = rest.split_at(len);
+            self.page_mut(page)?[offset..offset + len].copy_from_slice(chunk);
+            rest = tail;
         }
-        Err(crate::MemoryAccessError::AddressNotMapped)
+        self.track(AccessType::Write, address, value.len());
+        Ok(())
     }
 }
 
@@ -103,138 +136,348 @@ impl Device for DRAM {
     fn end_address(&self) -> usize {
         self.base_address + self.size
     }
+
+    fn check_write(&self, address: usize, size: usize) -> Result<(), MemoryAccessError> {
+        self.check_access(address, size)?;
+        if self
+            .chunks(address, size)
+            .iter()
+            .any(|(page, _, _)| !self.pages.contains_key(page))
+        {
+            return Err(MemoryAccessError::AddressNotMapped);
+        }
+        Ok(())
+    }
 }
 
 impl DRAM {
     pub fn new(base_address: usize, size: usize) -> Self {
         Self {
-            segments: Default::default(),
+            pages: Default::default(),
             base_address,
             size: size.next_power_of_two(),
             alignment: 1,
+            tracking: None,
         }
     }
 
     /// Allocate a new segment of memory
+    ///
+    /// Maps the pages covering `size_hint` bytes from `address_hint` and
+    /// returns the address of the first page. Fails if any of the pages is
+    /// already mapped.
     pub fn alloc(
         &mut self,
         address_hint: usize,
         size_hint: usize,
     ) -> Result<usize, MemoryAccessError> {
-        let address = address_hint & !(BLOCK_SIZE - 1);
-
-        if size_hint > self.size {
-            return Err(MemoryAccessError::OutOfBounds {
-                address: address_hint,
-                size: size_hint,
+        self.check_bounds(address_hint, size_hint)?;
+        let pages = self.page_range(address_hint, size_hint);
+        if let Some((&page, _)) = self.pages.range(pages.clone()).next() {
+            return Err(MemoryAccessError::AddressAlreadyMapped {
+                address: self.page_address(page),
             });
         }
+        self.map(address_hint, size_hint)?;
+        Ok(self.page_address(pages.start))
+    }
 
-        let size = size_hint + (address_hint & (DEFAULT_ALLOC_SIZE - 1));
-        let size = if size < DEFAULT_ALLOC_SIZE {
-            DEFAULT_ALLOC_SIZE
-        } else {
-            size.next_power_of_two()
-        };
-
-        if self.segments.is_empty() {
-            let segment = Segment {
-                start: address,
-                data: vec![0; size],
-            };
-            self.segments.insert(address, segment);
-            return Ok(address);
+    /// Maps the pages covering `size` bytes from `address` that are not mapped yet
+    pub fn map(&mut self, address: usize, size: usize) -> Result<(), MemoryAccessError> {
+        self.check_bounds(address, size)?;
+        for page in self.page_range(address, size) {
+            self.pages
+                .entry(page)
+                .or_insert_with(|| Arc::new([0; BLOCK_SIZE]));
         }
+        Ok(())
+    }
 
-        // check if the address is already mapped
-        for (addr, seg) in self.segments.iter_mut() {
-            if seg.contains(address) {
-                return Err(MemoryAccessError::AddressAlreadyMapped { address: *addr });
-            }
+    /// Returns if the byte at `address` is backed by an allocated page
+    pub fn is_mapped(&self, address: usize) -> bool {
+        self.check_bounds(address, 1).is_ok() && self.pages.contains_key(&self.locate(address).0)
+    }
+
+    /// Returns the number of allocated pages
+    pub fn mapped_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Starts or stops recording access counts and dirty bits
+    ///
+    /// Stopping discards the recorded data.
+    pub fn set_tracking(&mut self, enabled: bool) {
+        self.tracking = enabled.then(Default::default);
+    }
 
-            // extend the segment if the address is at the end
-            if seg.end() == address {
-                seg.data.resize(seg.data.len() + size, 0);
-                return Ok(*addr);
+    /// Returns if accesses are tracked
+    pub fn tracking(&self) -> bool {
+        self.tracking.is_some()
+    }
+
+    /// Returns the access counts of every accessed page in address order
+    ///
+    /// The pages are looked up lazily, accesses made while iterating are
+    /// counted and may show up in later items.
+    pub fn page_accesses(&self) -> impl Iterator<Item = PageAccesses> + '_ {
+        let mut next = 0;
+        std::iter::from_fn(move || {
+            let tracking = self.tracking.as_ref()?.borrow();
+            let (page, tracking) = tracking.range(next..).next()?;
+            next = page + 1;
+            Some(PageAccesses {
+                address: self.page_address(*page),
+                reads: tracking.reads,
+                writes: tracking.writes,
+                executes: tracking.executes,
+            })
+        })
+    }
+
+    /// Returns the addresses of all bytes written since tracking was enabled
+    /// or the dirty bits were cleared, in address order
+    ///
+    /// The dirty bits are scanned lazily, one byte at a time.
+    pub fn dirty_bytes(&self) -> impl Iterator<Item = usize> + '_ {
+        // the offset of the first byte not visited yet
+        let mut next = 0;
+        std::iter::from_fn(move || {
+            let tracking = self.tracking.as_ref()?.borrow();
+            for (page, tracking) in tracking.range(next / BLOCK_SIZE..) {
+                let start = if *page == next / BLOCK_SIZE {
+                    next % BLOCK_SIZE
+                } else {
+                    0
+                };
+                if let Some(offset) = first_dirty(&tracking.dirty, start) {
+                    next = page * BLOCK_SIZE + offset + 1;
+                    return Some(self.page_address(*page) + offset);
+                }
             }
+            None
+        })
+    }
+
+    /// Returns if the byte at `address` has been written since the dirty bits were cleared
+    pub fn is_dirty(&self, address: usize) -> bool {
+        if self.check_bounds(address, 1).is_err() {
+            return false;
+        }
+        let (page, offset) = self.locate(address);
+        self.tracking.as_ref().is_some_and(|tracking| {
+            tracking
+                .borrow()
+                .get(&page)
+                .is_some_and(|page| page.dirty[offset / 64] & (1 << (offset % 64)) != 0)
+        })
+    }
 
-            if address < *addr && address + size >= *addr {
-                let size = size.max(seg.end() - address);
-                let mut data = vec![0; size];
-                // copy the data from the old segment
-                data[(addr - address)..].copy_from_slice(&seg.data);
-                seg.data = data;
-                seg.start = address;
-                return Ok(*addr);
+    /// Clears the dirty bits, access counts are kept
+    pub fn clear_dirty(&mut self) {
+        if let Some(tracking) = &mut self.tracking {
+            for page in tracking.get_mut().values_mut() {
+                page.dirty = [0; BLOCK_SIZE / 64];
             }
         }
+    }
 
-        let segment = Segment {
-            start: address,
-            data: vec![0; size],
+    /// Records an access of `size` bytes if tracking is enabled
+    fn track(&self, access: AccessType, address: usize, size: usize) {
+        let Some(tracking) = &self.tracking else {
+            return;
         };
+        let mut tracking = tracking.borrow_mut();
+        for (page, offset, len) in self.chunks(address, size) {
+            let page = tracking.entry(page).or_insert(PageTracking {
+                reads: 0,
+                writes: 0,
+                executes: 0,
+                dirty: [0; BLOCK_SIZE / 64],
+            });
+            match access {
+                AccessType::Read => page.reads += 1,
+                AccessType::Execute => page.executes += 1,
+                AccessType::Write => {
+                    page.writes += 1;
+                    for byte in offset..offset + len {
+                        page.dirty[byte / 64] |= 1 << (byte % 64);
+                    }
+                }
+            }
+        }
+    }
 
-        self.segments.insert(address, segment);
+    /// Captures the contents of the DRAM
+    ///
+    /// Taking a snapshot is cheap: pages are shared until either side writes them.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            base_address: self.base_address,
+            size: self.size,
+            pages: self.pages.clone(),
+        }
+    }
 
-        Ok(address)
+    /// Restores the contents captured in `snapshot`
+    ///
+    /// The dirty bits are cleared, access counts are kept. Fails if the
+    /// snapshot was taken from a DRAM with a different address range, the
+    /// DRAM is left unchanged then.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), MemoryAccessError> {
+        if (snapshot.base_address, snapshot.size) != (self.base_address, self.size) {
+            return Err(MemoryAccessError::SnapshotMismatch {
+                address: snapshot.base_address,
+                size: snapshot.size,
+            });
+        }
+        self.pages = snapshot.pages.clone();
+        self.clear_dirty();
+        Ok(())
     }
 
+    /// Checks that the whole access lies within the DRAM
     fn check_access(&self, address: usize, size: usize) -> Result<(), crate::MemoryAccessError> {
-        if address % self.alignment != 0 {
+        if !address.is_multiple_of(self.alignment) {
             return Err(crate::MemoryAccessError::Unaligned { address });
         }
+        self.check_bounds(address, size)
+    }
 
-        if address < self.base_address || address >= self.base_address + self.size {
+    fn check_bounds(&self, address: usize, size: usize) -> Result<(), MemoryAccessError> {
+        let offset = address.wrapping_sub(self.base_address);
+        if address < self.base_address || offset >= self.size || size > self.size - offset {
             return Err(crate::MemoryAccessError::OutOfBounds { address, size });
         }
-
         Ok(())
     }
-}
 
-impl Segment {
-    pub(crate) fn end(&self) -> usize {
-        self.start + self.data.len()
+    /// Returns the page number and the offset into the page of an address
+    fn locate(&self, address: usize) -> (usize, usize) {
+        let offset = address - self.base_address;
+        (offset / BLOCK_SIZE, offset % BLOCK_SIZE)
+    }
+
+    fn page_address(&self, page: usize) -> usize {
+        self.base_address + page * BLOCK_SIZE
+    }
+
+    /// Returns the page numbers covering `size` bytes from `address`
+    fn page_range(&self, address: usize, size: usize) -> std::ops::Range<usize> {
+        let first = self.locate(address).0;
+        let last = self.locate(address + size.max(1) - 1).0;
+        first..last + 1
     }
 
-    pub(crate) fn contains(&self, address: usize) -> bool {
-        address >= self.start && address < self.end()
+    fn page(&self, page: usize) -> Result<&Page, MemoryAccessError> {
+        self.pages
+            .get(&page)
+            .map(|page| page.as_ref())
+            .ok_or(MemoryAccessError::AddressNotMapped)
     }
 
-    pub(crate) fn offset(&self, address: usize) -> usize {
-        address - self.start
+    fn page_mut(&mut self, page: usize) -> Result<&mut Page, MemoryAccessError> {
+        self.pages
+            .get_mut(&page)
+            .map(Arc::make_mut)
+            .ok_or(MemoryAccessError::AddressNotMapped)
+    }
+
+    /// Splits an access into the page number, page offset and length of its
+    /// pieces in every page
+    fn chunks(&self, address: usize, size: usize) -> Vec<(usize, usize, usize)> {
+        let mut chunks = Vec::new();
+        let (mut address, end) = (address, address + size);
+        while address < end {
+            let (page, offset) = self.locate(address);
+            let len = (end - address).min(BLOCK_SIZE - offset);
+            chunks.push((page, offset, len));
+            address += len;
+        }
+        chunks
     }
 }
 
-impl Addressable for Segment {
-    fn read_byte(&self, address: usize) -> Result<u8, crate::MemoryAccessError> {
-        assert!(self.contains(address));
-        let offset = self.offset(address);
-        Ok(self.data[offset])
+/// Returns the offset of the first dirty byte at or after `start` in a page
+fn first_dirty(dirty: &[u64; BLOCK_SIZE / 64], start: usize) -> Option<usize> {
+    let mut word = start / 64;
+    let mut bits = dirty.get(word)? & (u64::MAX << (start % 64));
+    while bits == 0 {
+        word += 1;
+        bits = *dirty.get(word)?;
+    }
+    Some(word * 64 + bits.trailing_zeros() as usize)
+}
+
+/// The contents of a DRAM at some point in time
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    base_address: usize,
+    size: usize,
+    pages: BTreeMap<usize, Arc<Page>>,
+}
+
+/// A page whose contents differ between two snapshots
+///
+/// Unmapped pages compare as zero-filled.
+#[derive(Debug, Clone)]
+pub struct PageDiff {
+    /// The address of the first byte of the page
+    pub address: usize,
+    old: Option<Arc<Page>>,
+    new: Option<Arc<Page>>,
+}
+
+impl PageDiff {
+    /// Returns if the page is mapped in the older snapshot
+    pub fn was_mapped(&self) -> bool {
+        self.old.is_some()
     }
 
-    fn read_bytes(&self, address: usize, size: usize) -> Result<&[u8], crate::MemoryAccessError> {
-        assert!(self.contains(address));
-        let offset = self.offset(address);
-        Ok(&self.data[offset..offset + size])
+    /// Returns if the page is mapped in the newer snapshot
+    pub fn is_mapped(&self) -> bool {
+        self.new.is_some()
     }
 
-    fn write_byte(&mut self, address: usize, value: u8) -> Result<(), crate::MemoryAccessError> {
-        assert!(self.contains(address));
-        let offset = self.offset(address);
-        self.data[offset] = value;
-        Ok(())
+    /// Returns the address, old and new value of every modified byte
+    pub fn changed_bytes(&self) -> impl Iterator<Item = (usize, u8, u8)> + '_ {
+        const ZERO: Page = [0; BLOCK_SIZE];
+        let old = self.old.as_deref().unwrap_or(&ZERO);
+        let new = self.new.as_deref().unwrap_or(&ZERO);
+        old.iter()
+            .zip(new.iter())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(offset, (old, new))| (self.address + offset, *old, *new))
     }
+}
 
-    fn write_bytes(
-        &mut self,
-        address: usize,
-        value: &[u8],
-    ) -> Result<(), crate::MemoryAccessError> {
-        assert!(self.contains(address));
-        let offset = self.offset(address);
-        self.data[offset..offset + value.len()].copy_from_slice(value);
-        Ok(())
+impl Snapshot {
+    /// Returns the pages that changed from `self` to `newer`, in address order
+    ///
+    /// Pages that were only mapped or unmapped are reported as well.
+    pub fn diff(&self, newer: &Snapshot) -> Vec<PageDiff> {
+        let pages: BTreeSet<usize> = self
+            .pages
+            .keys()
+            .chain(newer.pages.keys())
+            .copied()
+            .collect();
+        pages
+            .into_iter()
+            .filter_map(|page| {
+                let old = self.pages.get(&page);
+                let new = newer.pages.get(&page);
+                let unchanged = match (old, new) {
+                    (Some(old), Some(new)) => Arc::ptr_eq(old, new) || old == new,
+                    _ => false,
+                };
+                (!unchanged).then(|| PageDiff {
+                    address: newer.base_address + page * BLOCK_SIZE,
+                    old: old.cloned(),
+                    new: new.cloned(),
+                })
+            })
+            .collect()
     }
 }
 
@@ -257,4 +500,174 @@ mod test {
         let res = dram.read_byte(16 * 1024 + 1).unwrap();
         assert_eq!(res, 0x42);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_dram_sparse_pages() {
+        // a 64 GiB DRAM only stores the pages in use
+        let mut dram = DRAM::new(0x1_0000_0000, 64 << 30);
+        assert_eq!(dram.alloc(0x8_0000_0123, 0x1000).unwrap(), 0x8_0000_0000);
+        assert_eq!(dram.mapped_pages(), 2);
+        assert!(dram.is_mapped(0x8_0000_1fff));
+        assert!(!dram.is_mapped(0x8_0000_2000));
+        assert!(matches!(
+            dram.alloc(0x8_0000_1800, 0x1000),
+            Err(MemoryAccessError::AddressAlreadyMapped {
+                address: 0x8_0000_1000
+            })
+        ));
+
+        // mapping is idempotent and fills the holes
+        dram.map(0x8_0000_0000, 0x3000).unwrap();
+        assert_eq!(dram.mapped_pages(), 3);
+        dram.write_bytes(0x8_0000_2ffe, &[1, 2]).unwrap();
+        assert_eq!(*dram.read_bytes(0x8_0000_2ffe, 2).unwrap(), [1, 2]);
+        assert!(!dram.is_mapped(0xffff_ffff));
+    }
+
+    #[test]
+    fn test_dram_spanning_access() {
+        let mut dram = DRAM::new(0x1000, 0x4000);
+        dram.alloc(0x1000, 0x1000).unwrap();
+        dram.alloc(0x2000, 0x1000).unwrap();
+        dram.alloc(0x4000, 0x1000).unwrap();
+
+        dram.write_bytes(0x1ffe, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(*dram.read_bytes(0x1ffe, 4).unwrap(), [1, 2, 3, 4]);
+        assert!(matches!(
+            dram.read_bytes(0x2000, 2).unwrap(),
+            Cow::Borrowed(_)
+        ));
+
+        // the page at 0x3000 is not mapped and nothing is written
+        assert!(matches!(
+            dram.write_bytes(0x2fff, &[5, 6]),
+            Err(MemoryAccessError::AddressNotMapped)
+        ));
+        assert_eq!(dram.read_byte(0x2fff).unwrap(), 0);
+
+        // the access is checked against its full size
+        assert!(matches!(
+            dram.read_bytes(0x4ffe, 4),
+            Err(MemoryAccessError::OutOfBounds {
+                address: 0x4ffe,
+                size: 4
+            })
+        ));
+        assert!(matches!(
+            dram.write_bytes(0xfff, &[0; 2]),
+            Err(MemoryAccessError::OutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn test_dram_snapshot() {
+        let mut dram = DRAM::new(0, 1 << 20);
+        dram.alloc(0, 0x2000).unwrap();
+        dram.write_bytes(0x10, &[1, 2, 3]).unwrap();
+        let before = dram.snapshot();
+
+        dram.write_bytes(0x11, &[2, 7]).unwrap();
+        dram.write_byte(0x1fff, 9).unwrap();
+        dram.alloc(0x8000, 1).unwrap();
+        let after = dram.snapshot();
+
+        let diff = before.diff(&after);
+        assert_eq!(
+            diff.iter().map(|page| page.address).collect::<Vec<_>>(),
+            [0, 0x1000, 0x8000]
+        );
+        assert_eq!(diff[0].changed_bytes().collect::<Vec<_>>(), [(0x12, 3, 7)]);
+        assert_eq!(
+            diff[1].changed_bytes().collect::<Vec<_>>(),
+            [(0x1fff, 0, 9)]
+        );
+        // a fresh page is reported even if it only holds zeros
+        assert!(!diff[2].was_mapped() && diff[2].is_mapped());
+        assert_eq!(diff[2].changed_bytes().count(), 0);
+
+        dram.restore(&before).unwrap();
+        assert_eq!(*dram.read_bytes(0x10, 3).unwrap(), [1, 2, 3]);
+        assert!(!dram.is_mapped(0x8000));
+        assert!(before.diff(&dram.snapshot()).is_empty());
+        // restoring does not alias the snapshot
+        dram.write_byte(0x10, 0xff).unwrap();
+        assert_eq!(before.diff(&dram.snapshot()).len(), 1);
+        assert!(after.diff(&after).is_empty());
+
+        // a snapshot of another address range is rejected
+        let mut other = DRAM::new(0x1000, 1 << 20);
+        assert!(matches!(
+            other.restore(&before),
+            Err(MemoryAccessError::SnapshotMismatch {
+                address: 0,
+                size: 0x100000
+            })
+        ));
+        assert_eq!(other.start_address(), 0x1000);
+        assert_eq!(other.mapped_pages(), 0);
+    }
+
+    #[test]
+    fn test_dram_tracking() {
+        let mut dram = DRAM::new(0, 1 << 20);
+        dram.alloc(0, 0x3000).unwrap();
+        dram.write_byte(0x10, 1).unwrap();
+        assert_eq!(dram.page_accesses().count(), 0);
+
+        dram.set_tracking(true);
+        dram.write_bytes(0xffe, &[1, 2, 3]).unwrap();
+        dram.read_bytes(0x1000, 8).unwrap();
+        dram.fetch_byte(0x2000).unwrap();
+        dram.fetch_byte(0x2001).unwrap();
+        assert!(dram.read_byte(0x4000).is_err());
+
+        let pages: Vec<_> = dram.page_accesses().collect();
+        assert_eq!(
+            pages,
+            [
+                PageAccesses {
+                    address: 0,
+                    reads: 0,
+                    writes: 1,
+                    executes: 0
+                },
+                PageAccesses {
+                    address: 0x1000,
+                    reads: 1,
+                    writes: 1,
+                    executes: 0
+                },
+                PageAccesses {
+                    address: 0x2000,
+                    reads: 0,
+                    writes: 0,
+                    executes: 2
+                },
+            ]
+        );
+        assert_eq!(pages[2].count(AccessType::Execute), 2);
+        assert_eq!(
+            dram.dirty_bytes().collect::<Vec<_>>(),
+            [0xffe, 0xfff, 0x1000]
+        );
+        assert!(dram.is_dirty(0xfff) && !dram.is_dirty(0x10));
+
+        dram.clear_dirty();
+        assert_eq!(dram.dirty_bytes().count(), 0);
+        assert_eq!(dram.page_accesses().count(), 3);
+
+        // rewinding forgets the writes made since the snapshot
+        let snapshot = dram.snapshot();
+        dram.write_bytes(0x203f, &[1, 2]).unwrap();
+        dram.write_byte(0x2fff, 3).unwrap();
+        assert_eq!(
+            dram.dirty_bytes().collect::<Vec<_>>(),
+            [0x203f, 0x2040, 0x2fff]
+        );
+        dram.restore(&snapshot).unwrap();
+        assert_eq!(dram.dirty_bytes().count(), 0);
+        assert_eq!(dram.page_accesses().nth(2).unwrap().writes, 2);
+        dram.set_tracking(false);
+        assert_eq!(dram.page_accesses().count(), 0);
+    }
+}
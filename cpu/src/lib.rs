@@ -1,7 +1,14 @@
+use std::borrow::Cow;
+
+use paste::paste;
 use thiserror::Error;
 
+pub mod bus;
 pub mod device;
+pub mod rom;
 pub mod simd;
+pub mod timer;
+pub mod uart;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Endianness {
@@ -15,9 +22,69 @@ pub struct CpuFeatures {
     pub unaligned_memory_access: bool,
 }
 
+/// The reason the CPU stopped executing instructions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The CPU executed a halt instruction
+    Halted,
+    /// A breakpoint or the predicate of `run_until` was hit
+    Breakpoint { address: usize },
+    /// The instruction at `address` raised an exception
+    Exception { address: usize, description: String },
+    /// The requested number of instructions has been executed
+    BudgetExhausted,
+    /// A device raised the interrupt line `line`
+    ///
+    /// Lines raised together are reported by consecutive steps, lowest first.
+    /// A halted CPU wakes up when it reports an interrupt.
+    Interrupt { line: u8 },
+}
+
 pub trait Cpu {
-    /// Runs the CPU
-    fn run(&mut self);
+    /// Executes a single instruction
+    ///
+    /// Returns `None` if the instruction retired and execution can continue.
+    fn step(&mut self) -> Option<StopReason>;
+
+    /// Runs the CPU until it stops
+    fn run(&mut self) -> StopReason {
+        loop {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
+    }
+
+    /// Runs the CPU for at most `count` instructions
+    fn run_for(&mut self, count: u64) -> StopReason {
+        for _ in 0..count {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
+        StopReason::BudgetExhausted
+    }
+
+    /// Runs the CPU until `predicate` holds after an instruction retires
+    fn run_until<F>(&mut self, mut predicate: F) -> StopReason
+    where
+        F: FnMut(&Self) -> bool,
+        Self: Sized,
+    {
+        loop {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+            if predicate(self) {
+                return StopReason::Breakpoint {
+                    address: self.program_counter(),
+                };
+            }
+        }
+    }
+
+    /// Returns the address of the next instruction to execute
+    fn program_counter(&self) -> usize;
 
     /// Returns the size of the general register in bytes
     ///
@@ -28,7 +95,9 @@ pub trait Cpu {
     fn endianness(&self) -> Endianness;
 
     /// Add a device to the CPU
-    fn add_device(&mut self, device: Box<dyn Device>);
+    ///
+    /// Fails if the address range of the device overlaps an attached device.
+    fn add_device(&mut self, device: Box<dyn Device>) -> Result<(), MemoryAccessError>;
 
     /// Returns if the CPU has virtual memory enabled
     fn features(&self) -> CpuFeatures;
@@ -41,18 +110,184 @@ pub trait Device: Addressable {
     fn start_address(&self) -> usize;
     /// Returns the end address of the device
     fn end_address(&self) -> usize;
+
+    /// Checks that a write of `size` bytes at `address` would succeed, without writing
+    ///
+    /// The bus checks every device before a write spanning several devices,
+    /// so devices that reject writes need to report it here.
+    fn check_write(&self, _address: usize, _size: usize) -> Result<(), MemoryAccessError> {
+        Ok(())
+    }
+
+    /// Advances the device by the cycles and instructions that elapsed
+    ///
+    /// The device drives its interrupt lines through `lines`, which keeps the
+    /// levels set during previous ticks.
+    fn tick(&mut self, _tick: Tick, _lines: &mut InterruptLines) {}
+
+    /// Returns the device to its power-on state
+    fn reset(&mut self) {}
+}
+
+/// The time that elapsed since a device was last ticked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tick {
+    /// The number of clock cycles
+    pub cycles: u64,
+    /// The number of retired instructions
+    pub instructions: u64,
+}
+
+/// A set of level-triggered interrupt lines, numbered 0 to 255
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InterruptLines {
+    bits: [u64; 4],
+}
+
+impl InterruptLines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asserts `line`
+    pub fn raise(&mut self, line: u8) {
+        self.bits[line as usize / 64] |= 1 << (line % 64);
+    }
+
+    /// Deasserts `line`
+    pub fn lower(&mut self, line: u8) {
+        self.bits[line as usize / 64] &= !(1 << (line % 64));
+    }
+
+    /// Asserts or deasserts `line`
+    pub fn set(&mut self, line: u8, raised: bool) {
+        if raised {
+            self.raise(line);
+        } else {
+            self.lower(line);
+        }
+    }
+
+    /// Returns if `line` is asserted
+    pub fn is_raised(&self, line: u8) -> bool {
+        self.bits[line as usize / 64] & (1 << (line % 64)) != 0
+    }
+
+    /// Returns the asserted lines in ascending order
+    pub fn raised(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(|line| self.is_raised(*line))
+    }
+
+    /// Returns the lines asserted in `self` or `other`
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            bits: std::array::from_fn(|index| self.bits[index] | other.bits[index]),
+        }
+    }
+
+    /// Returns the lines asserted in `self` but not in `other`
+    pub fn difference(&self, other: &Self) -> Self {
+        Self {
+            bits: std::array::from_fn(|index| self.bits[index] & !other.bits[index]),
+        }
+    }
+}
+
+/// Defines `read_<type>` and `write_<type>` for unsigned integer types
+macro_rules! typed_access {
+    ($($type:ty),*) => {
+        paste! {
+            $(
+                #[doc = "Reads a `" $type "` stored with the given endianness"]
+                fn [<read_ $type>](
+                    &self,
+                    address: usize,
+                    endianness: Endianness,
+                ) -> Result<$type, MemoryAccessError> {
+                    let bytes = self.read_bytes(address, size_of::<$type>())?;
+                    let bytes = bytes.as_ref().try_into().expect("read_bytes returned a wrong size");
+                    Ok(match endianness {
+                        Endianness::LittleEndian => $type::from_le_bytes(bytes),
+                        Endianness::BigEndian => $type::from_be_bytes(bytes),
+                    })
+                }
+
+                #[doc = "Writes a `" $type "` with the given endianness"]
+                fn [<write_ $type>](
+                    &mut self,
+                    address: usize,
+                    value: $type,
+                    endianness: Endianness,
+                ) -> Result<(), MemoryAccessError> {
+                    match endianness {
+                        Endianness::LittleEndian => self.write_bytes(address, &value.to_le_bytes()),
+                        Endianness::BigEndian => self.write_bytes(address, &value.to_be_bytes()),
+                    }
+                }
+            )*
+        }
+    };
 }
 
 pub trait Addressable {
     /// Reads a byte from the addressable memory
     fn read_byte(&self, address: usize) -> Result<u8, MemoryAccessError>;
     /// Reads a slice of bytes from the addressable memory
-    fn read_bytes(&self, address: usize, size: usize) -> Result<&[u8], MemoryAccessError>;
+    ///
+    /// The bytes are borrowed if they are stored contiguously, accesses
+    /// spanning several regions return a copy.
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, MemoryAccessError>;
+
+    /// Reads a byte for instruction fetch
+    ///
+    /// Behaves like `read_byte` unless the memory distinguishes execute accesses.
+    fn fetch_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        self.read_byte(address)
+    }
 
     /// Writes a byte to the addressable memory
     fn write_byte(&mut self, address: usize, value: u8) -> Result<(), MemoryAccessError>;
     /// Writes a slice of bytes to the addressable memory
     fn write_bytes(&mut self, address: usize, value: &[u8]) -> Result<(), MemoryAccessError>;
+
+    typed_access!(u16, u32, u64, u128);
+
+    /// Reads an unsigned integer of `size` bytes (1 to 8), zero-extended to 64 bits
+    fn read_uint(
+        &self,
+        address: usize,
+        size: usize,
+        endianness: Endianness,
+    ) -> Result<u64, MemoryAccessError> {
+        assert!((1..=8).contains(&size), "invalid integer size {size}");
+        let bytes = self.read_bytes(address, size)?;
+        let mut value = [0; 8];
+        Ok(match endianness {
+            Endianness::LittleEndian => {
+                value[..size].copy_from_slice(&bytes);
+                u64::from_le_bytes(value)
+            }
+            Endianness::BigEndian => {
+                value[8 - size..].copy_from_slice(&bytes);
+                u64::from_be_bytes(value)
+            }
+        })
+    }
+
+    /// Writes the low `size` bytes (1 to 8) of `value`
+    fn write_uint(
+        &mut self,
+        address: usize,
+        size: usize,
+        value: u64,
+        endianness: Endianness,
+    ) -> Result<(), MemoryAccessError> {
+        assert!((1..=8).contains(&size), "invalid integer size {size}");
+        match endianness {
+            Endianness::LittleEndian => self.write_bytes(address, &value.to_le_bytes()[..size]),
+            Endianness::BigEndian => self.write_bytes(address, &value.to_be_bytes()[8 - size..]),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -86,4 +321,44 @@ pub enum MemoryAccessError {
     /// The address is already mapped
     #[error("The address {address:#x} is already mapped")]
     AddressAlreadyMapped { address: usize },
+
+    /// The address is not writable
+    #[error("The address {address:#x} is write-protected")]
+    WriteProtected { address: usize },
+
+    /// The snapshot was taken from a different address range
+    #[error("The snapshot of {size} bytes at address {address:#x} does not match the memory")]
+    SnapshotMismatch { address: usize, size: usize },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::DRAM;
+    use Endianness::*;
+
+    #[test]
+    fn test_typed_access() {
+        let mut dram = DRAM::new(0, 0x1000);
+        dram.alloc(0, 0x1000).unwrap();
+
+        dram.write_u32(0x10, 0x1234_5678, LittleEndian).unwrap();
+        assert_eq!(*dram.read_bytes(0x10, 4).unwrap(), [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(dram.read_u32(0x10, BigEndian).unwrap(), 0x7856_3412);
+        assert_eq!(dram.read_u16(0x12, LittleEndian).unwrap(), 0x1234);
+
+        let value = 0x0011_2233_4455_6677_8899_aabb_ccdd_eeffu128;
+        dram.write_u128(0x20, value, BigEndian).unwrap();
+        assert_eq!(dram.read_u128(0x20, BigEndian).unwrap(), value);
+        assert_eq!(
+            dram.read_u64(0x20, BigEndian).unwrap(),
+            (value >> 64) as u64
+        );
+        assert_eq!(dram.read_byte(0x2f).unwrap(), 0xff);
+
+        dram.write_uint(0x40, 3, 0xaabbcc, BigEndian).unwrap();
+        assert_eq!(*dram.read_bytes(0x40, 3).unwrap(), [0xaa, 0xbb, 0xcc]);
+        assert_eq!(dram.read_uint(0x40, 3, LittleEndian).unwrap(), 0xccbbaa);
+        assert!(dram.read_u64(0xffc, LittleEndian).is_err());
+    }
 }
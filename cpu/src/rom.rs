@@ -0,0 +1,255 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use memmap2::{Mmap, MmapMut, MmapOptions};
+
+use crate::{Addressable, Device, MemoryAccessError};
+
+/// A read-only memory holding a fixed image
+///
+/// Every write fails with `WriteProtected`. Clones share the image.
+#[derive(Debug, Clone)]
+pub struct ROM {
+    data: Arc<[u8]>,
+    base_address: usize,
+}
+
+impl ROM {
+    /// Creates a ROM at `base_address` holding `data`
+    pub fn new(base_address: usize, data: impl Into<Arc<[u8]>>) -> Self {
+        Self {
+            data: data.into(),
+            base_address,
+        }
+    }
+
+    /// Returns the contents of the ROM
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Addressable for ROM {
+    fn read_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        let offset = offset(self.base_address, self.data.len(), address, 1)?;
+        Ok(self.data[offset])
+    }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, MemoryAccessError> {
+        let offset = offset(self.base_address, self.data.len(), address, size)?;
+        Ok(Cow::Borrowed(&self.data[offset..offset + size]))
+    }
+
+    fn write_byte(&mut self, address: usize, _value: u8) -> Result<(), MemoryAccessError> {
+        self.check_write(address, 1)
+    }
+
+    fn write_bytes(&mut self, address: usize, value: &[u8]) -> Result<(), MemoryAccessError> {
+        self.check_write(address, value.len())
+    }
+}
+
+impl Device for ROM {
+    fn name(&self) -> &str {
+        "ROM"
+    }
+
+    fn start_address(&self) -> usize {
+        self.base_address
+    }
+
+    fn end_address(&self) -> usize {
+        self.base_address + self.data.len()
+    }
+
+    fn check_write(&self, address: usize, size: usize) -> Result<(), MemoryAccessError> {
+        offset(self.base_address, self.data.len(), address, size)?;
+        Err(MemoryAccessError::WriteProtected { address })
+    }
+}
+
+/// How writes to a `MappedFile` are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    /// Writes fail with `WriteProtected`
+    ReadOnly,
+    /// Writes modify a private copy of the touched pages, the file is left unchanged
+    CopyOnWrite,
+}
+
+#[derive(Debug)]
+enum Mapping {
+    ReadOnly(Mmap),
+    CopyOnWrite(MmapMut),
+}
+
+/// A device backed by a memory-mapped host file
+///
+/// The file is mapped lazily by the host, so large images are loaded without
+/// copying them up front.
+#[derive(Debug)]
+pub struct MappedFile {
+    mapping: Mapping,
+    base_address: usize,
+    name: String,
+}
+
+impl MappedFile {
+    /// Maps the file at `path` to `base_address`
+    pub fn open(path: impl AsRef<Path>, base_address: usize, mode: FileMode) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        // SAFETY: the mapping is only sound while no other process truncates or
+        // modifies the file, which cannot be enforced and is left to the caller
+        let mapping = unsafe {
+            match mode {
+                FileMode::ReadOnly => Mapping::ReadOnly(Mmap::map(&file)?),
+                FileMode::CopyOnWrite => Mapping::CopyOnWrite(MmapOptions::new().map_copy(&file)?),
+            }
+        };
+        Ok(Self {
+            mapping,
+            base_address,
+            name: path.file_name().map_or_else(
+                || path.display().to_string(),
+                |name| name.to_string_lossy().into(),
+            ),
+        })
+    }
+
+    /// Returns how writes are handled
+    pub fn mode(&self) -> FileMode {
+        match self.mapping {
+            Mapping::ReadOnly(_) => FileMode::ReadOnly,
+            Mapping::CopyOnWrite(_) => FileMode::CopyOnWrite,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match &self.mapping {
+            Mapping::ReadOnly(mmap) => mmap,
+            Mapping::CopyOnWrite(mmap) => mmap,
+        }
+    }
+
+    /// Returns the writable bytes covering `size` bytes from `address`
+    fn data_mut(&mut self, address: usize, size: usize) -> Result<&mut [u8], MemoryAccessError> {
+        let offset = offset(self.base_address, self.data().len(), address, size)?;
+        match &mut self.mapping {
+            Mapping::ReadOnly(_) => Err(MemoryAccessError::WriteProtected { address }),
+            Mapping::CopyOnWrite(mmap) => Ok(&mut mmap[offset..offset + size]),
+        }
+    }
+}
+
+impl Addressable for MappedFile {
+    fn read_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        let offset = offset(self.base_address, self.data().len(), address, 1)?;
+        Ok(self.data()[offset])
+    }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, MemoryAccessError> {
+        let offset = offset(self.base_address, self.data().len(), address, size)?;
+        Ok(Cow::Borrowed(&self.data()[offset..offset + size]))
+    }
+
+    fn write_byte(&mut self, address: usize, value: u8) -> Result<(), MemoryAccessError> {
+        self.data_mut(address, 1)?[0] = value;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, address: usize, value: &[u8]) -> Result<(), MemoryAccessError> {
+        self.data_mut(address, value.len())?.copy_from_slice(value);
+        Ok(())
+    }
+}
+
+impl Device for MappedFile {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start_address(&self) -> usize {
+        self.base_address
+    }
+
+    fn end_address(&self) -> usize {
+        self.base_address + self.data().len()
+    }
+
+    fn check_write(&self, address: usize, size: usize) -> Result<(), MemoryAccessError> {
+        offset(self.base_address, self.data().len(), address, size)?;
+        match self.mapping {
+            Mapping::ReadOnly(_) => Err(MemoryAccessError::WriteProtected { address }),
+            Mapping::CopyOnWrite(_) => Ok(()),
+        }
+    }
+}
+
+/// Returns the offset of an access of `size` bytes into a region of `len` bytes
+fn offset(
+    base: usize,
+    len: usize,
+    address: usize,
+    size: usize,
+) -> Result<usize, MemoryAccessError> {
+    let offset = address.wrapping_sub(base);
+    if address < base || offset >= len || size > len - offset {
+        return Err(MemoryAccessError::OutOfBounds { address, size });
+    }
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+
+    #[test]
+    fn test_rom() {
+        let mut rom = ROM::new(0x100, vec![1, 2, 3, 4]);
+        assert_eq!(rom.read_byte(0x101).unwrap(), 2);
+        assert_eq!(*rom.read_bytes(0x102, 2).unwrap(), [3, 4]);
+        assert!(matches!(
+            rom.write_byte(0x100, 0),
+            Err(MemoryAccessError::WriteProtected { address: 0x100 })
+        ));
+        assert!(matches!(
+            rom.read_bytes(0x103, 2),
+            Err(MemoryAccessError::OutOfBounds { .. })
+        ));
+
+        let mut bus = Bus::new();
+        bus.attach(Box::new(rom)).unwrap();
+        assert!(matches!(
+            bus.write_bytes(0x102, &[0, 0]),
+            Err(MemoryAccessError::WriteProtected { address: 0x102 })
+        ));
+        assert_eq!(bus.device_at(0x103).unwrap().name(), "ROM");
+    }
+
+    #[test]
+    fn test_mapped_file() {
+        let path = std::env::temp_dir().join(format!("visual-cpu-{}.bin", std::process::id()));
+        std::fs::write(&path, (0..=255).collect::<Vec<u8>>()).unwrap();
+
+        let mut file = MappedFile::open(&path, 0x1000, FileMode::ReadOnly).unwrap();
+        assert_eq!(file.end_address(), 0x1100);
+        assert_eq!(*file.read_bytes(0x10fe, 2).unwrap(), [0xfe, 0xff]);
+        assert!(matches!(
+            file.write_byte(0x1000, 0),
+            Err(MemoryAccessError::WriteProtected { .. })
+        ));
+
+        let mut copy = MappedFile::open(&path, 0, FileMode::CopyOnWrite).unwrap();
+        copy.write_bytes(0x10, &[0xaa, 0xbb]).unwrap();
+        assert_eq!(*copy.read_bytes(0xf, 3).unwrap(), [0xf, 0xaa, 0xbb]);
+        assert_eq!(file.read_byte(0x1010).unwrap(), 0x10);
+        assert_eq!(std::fs::read(&path).unwrap()[0x10], 0x10);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
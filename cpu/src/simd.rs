@@ -6,7 +6,7 @@ use paste::paste;
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Simd<T: Num + Copy, const N: usize>([T; N]);
 
-impl <T: Num + Copy, const N: usize> Simd<T, N> {
+impl<T: Num + Copy, const N: usize> Simd<T, N> {
     pub fn new() -> Self {
         Self([T::zero(); N])
     }
@@ -16,7 +16,13 @@ impl <T: Num + Copy, const N: usize> Simd<T, N> {
     }
 }
 
-impl <T: Num + Copy, const N: usize> Index<usize> for Simd<T, N> {
+impl<T: Num + Copy, const N: usize> Default for Simd<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Num + Copy, const N: usize> Index<usize> for Simd<T, N> {
     type Output = T;
 
     fn index(&self, index: usize) -> &Self::Output {
@@ -0,0 +1,214 @@
+use std::borrow::Cow;
+
+use crate::{Addressable, Device, InterruptLines, MemoryAccessError, Tick};
+
+/// The number of 64-bit registers of the timer
+const REGISTERS: usize = 4;
+
+/// Control register, see the `CONTROL_*` bits
+const CONTROL: usize = 0;
+/// The number of cycles counted since the timer was started or reloaded
+const COUNTER: usize = 1;
+/// The counter value at which the timer expires
+const COMPARATOR: usize = 2;
+/// Interrupt status, writing a set bit clears it
+const STATUS: usize = 3;
+
+/// The timer counts cycles
+pub const CONTROL_ENABLE: u64 = 1 << 0;
+/// The counter restarts from zero when the timer expires
+pub const CONTROL_PERIODIC: u64 = 1 << 1;
+/// The timer asserts its interrupt line while the status bit is set
+pub const CONTROL_INTERRUPT: u64 = 1 << 2;
+
+/// A programmable interval timer in the style of an HPET comparator
+///
+/// The timer counts the cycles it is ticked and sets its status bit when the
+/// counter reaches the comparator. One-shot timers stop on expiry, periodic
+/// timers restart from zero. The status bit stays set until the guest clears
+/// it, so the interrupt is level-triggered.
+///
+/// The registers are 64-bit little-endian values at offsets 0x00 (control),
+/// 0x08 (counter), 0x10 (comparator) and 0x18 (status).
+#[derive(Debug, Clone)]
+pub struct Timer {
+    base_address: usize,
+    /// The interrupt line asserted on expiry
+    line: u8,
+    registers: [u64; REGISTERS],
+}
+
+impl Timer {
+    pub fn new(base_address: usize, line: u8) -> Self {
+        Self {
+            base_address,
+            line,
+            registers: [0; REGISTERS],
+        }
+    }
+
+    /// Returns the number of cycles counted
+    pub fn counter(&self) -> u64 {
+        self.registers[COUNTER]
+    }
+
+    /// Returns if the timer has expired and the guest has not acknowledged it yet
+    pub fn expired(&self) -> bool {
+        self.registers[STATUS] & 1 != 0
+    }
+
+    fn offset(&self, address: usize, size: usize) -> Result<usize, MemoryAccessError> {
+        let offset = address.wrapping_sub(self.base_address);
+        if address < self.base_address || offset >= REGISTERS * 8 || size > REGISTERS * 8 - offset {
+            return Err(MemoryAccessError::OutOfBounds { address, size });
+        }
+        Ok(offset)
+    }
+
+    /// Advances the counter by `cycles`
+    fn count(&mut self, cycles: u64) {
+        let control = self.registers[CONTROL];
+        if control & CONTROL_ENABLE == 0 {
+            return;
+        }
+        let counter = self.registers[COUNTER].saturating_add(cycles);
+        let comparator = self.registers[COMPARATOR];
+        if self.registers[COUNTER] < comparator && counter >= comparator {
+            self.registers[STATUS] |= 1;
+            if control & CONTROL_PERIODIC != 0 {
+                // keep the cycles counted past the comparator
+                self.registers[COUNTER] = (counter - comparator) % comparator;
+                return;
+            }
+            self.registers[CONTROL] &= !CONTROL_ENABLE;
+        }
+        self.registers[COUNTER] = counter;
+    }
+
+    fn write_register(&mut self, register: usize, value: u64) {
+        match register {
+            STATUS => self.registers[STATUS] &= !value,
+            _ => self.registers[register] = value,
+        }
+    }
+}
+
+impl Addressable for Timer {
+    fn read_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        Ok(self.read_bytes(address, 1)?[0])
+    }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, MemoryAccessError> {
+        let offset = self.offset(address, size)?;
+        let bytes: Vec<u8> = self
+            .registers
+            .iter()
+            .flat_map(|register| register.to_le_bytes())
+            .collect();
+        Ok(Cow::Owned(bytes[offset..offset + size].to_vec()))
+    }
+
+    fn write_byte(&mut self, address: usize, value: u8) -> Result<(), MemoryAccessError> {
+        self.write_bytes(address, &[value])
+    }
+
+    fn write_bytes(&mut self, address: usize, value: &[u8]) -> Result<(), MemoryAccessError> {
+        let offset = self.offset(address, value.len())?;
+        // registers are written as a whole, bytes not covered by the access are kept
+        for register in offset / 8..(offset + value.len()).div_ceil(8) {
+            let mut bytes = match register {
+                STATUS => [0; 8],
+                _ => self.registers[register].to_le_bytes(),
+            };
+            for (index, byte) in bytes.iter_mut().enumerate() {
+                if let Some(value) = (register * 8 + index)
+                    .checked_sub(offset)
+                    .and_then(|index| value.get(index))
+                {
+                    *byte = *value;
+                }
+            }
+            self.write_register(register, u64::from_le_bytes(bytes));
+        }
+        Ok(())
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str {
+        "Timer"
+    }
+
+    fn start_address(&self) -> usize {
+        self.base_address
+    }
+
+    fn end_address(&self) -> usize {
+        self.base_address + REGISTERS * 8
+    }
+
+    fn tick(&mut self, tick: Tick, lines: &mut InterruptLines) {
+        self.count(tick.cycles);
+        lines.set(
+            self.line,
+            self.registers[CONTROL] & CONTROL_INTERRUPT != 0 && self.expired(),
+        );
+    }
+
+    fn reset(&mut self) {
+        self.registers = [0; REGISTERS];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Endianness::LittleEndian;
+
+    fn cycles(cycles: u64) -> Tick {
+        Tick {
+            cycles,
+            instructions: 0,
+        }
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut timer = Timer::new(0x1000, 2);
+        let mut lines = InterruptLines::new();
+        timer.write_u64(0x1010, 3, LittleEndian).unwrap();
+        timer
+            .write_u64(
+                0x1000,
+                CONTROL_ENABLE | CONTROL_PERIODIC | CONTROL_INTERRUPT,
+                LittleEndian,
+            )
+            .unwrap();
+
+        timer.tick(cycles(2), &mut lines);
+        assert!(!lines.is_raised(2));
+        timer.tick(cycles(1), &mut lines);
+        assert!(lines.is_raised(2));
+        assert_eq!(timer.read_u64(0x1008, LittleEndian).unwrap(), 0);
+
+        // the interrupt stays asserted until it is acknowledged
+        timer.tick(cycles(1), &mut lines);
+        assert!(timer.expired());
+        timer.write_byte(0x1018, 1).unwrap();
+        timer.tick(cycles(0), &mut lines);
+        assert!(!lines.is_raised(2));
+        assert_eq!(timer.counter(), 1);
+
+        // a one-shot timer stops on expiry
+        timer.write_byte(0x1000, CONTROL_ENABLE as u8).unwrap();
+        timer.tick(cycles(5), &mut lines);
+        assert!(timer.expired());
+        assert_eq!(timer.read_byte(0x1000).unwrap(), 0);
+        assert_eq!(timer.counter(), 6);
+        assert!(timer.read_bytes(0x101c, 8).is_err());
+
+        timer.reset();
+        assert!(!timer.expired());
+        assert_eq!(timer.counter(), 0);
+    }
+}
@@ -0,0 +1,396 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::{Addressable, Device, InterruptLines, MemoryAccessError, Tick};
+
+/// The number of registers of the UART
+const REGISTERS: usize = 8;
+
+/// Receiver buffer (read) / transmitter holding register (write), divisor latch low with DLAB
+const RBR_THR: usize = 0;
+/// Interrupt enable register, divisor latch high with DLAB
+const IER: usize = 1;
+/// Interrupt identification (read) / FIFO control (write)
+const IIR_FCR: usize = 2;
+/// Line control register
+const LCR: usize = 3;
+/// Modem control register
+const MCR: usize = 4;
+/// Line status register
+const LSR: usize = 5;
+/// Modem status register
+const MSR: usize = 6;
+/// Scratch register
+const SCR: usize = 7;
+
+/// Divisor latch access bit of the LCR
+const LCR_DLAB: u8 = 1 << 7;
+/// Loopback bit of the MCR
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+/// Data ready bit of the LSR
+pub const LSR_DATA_READY: u8 = 1 << 0;
+/// Transmitter holding register empty bit of the LSR
+pub const LSR_THR_EMPTY: u8 = 1 << 5;
+/// Transmitter empty bit of the LSR
+pub const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+/// Received data available interrupt enable bit of the IER
+const IER_RECEIVED: u8 = 1 << 0;
+/// Transmitter holding register empty interrupt enable bit of the IER
+const IER_THR_EMPTY: u8 = 1 << 1;
+
+/// The host side of a UART
+///
+/// Handles share the buffers with the UART, so the host can keep talking to
+/// a UART after attaching it to a bus.
+#[derive(Debug, Clone, Default)]
+pub struct Console {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+#[derive(Debug, Default)]
+struct Buffers {
+    /// The bytes received from the host, not read by the guest yet
+    input: VecDeque<u8>,
+    /// The bytes transmitted by the guest
+    output: Vec<u8>,
+}
+
+impl Console {
+    /// Queues bytes to be received by the guest
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.buffers.borrow_mut().input.extend(bytes);
+    }
+
+    /// Returns the bytes transmitted by the guest
+    pub fn output(&self) -> Vec<u8> {
+        self.buffers.borrow().output.clone()
+    }
+
+    /// Removes and returns the bytes transmitted by the guest
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.buffers.borrow_mut().output)
+    }
+
+    fn has_input(&self) -> bool {
+        !self.buffers.borrow().input.is_empty()
+    }
+}
+
+/// A 16550-compatible UART
+///
+/// Transmitted bytes are collected into the console and optionally echoed to
+/// the host stdout, received bytes are fed through the console. Transmission
+/// completes immediately, so the transmitter is always empty.
+#[derive(Debug)]
+pub struct Uart {
+    base_address: usize,
+    console: Console,
+    /// If transmitted bytes are echoed to the host stdout
+    echo: bool,
+    /// The line asserted while an enabled interrupt condition is pending
+    line: Option<u8>,
+    ier: u8,
+    /// The transmitter holding register empty interrupt is pending, it is
+    /// acknowledged by reading the IIR or writing the THR
+    thre_pending: Cell<bool>,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    fifo_enabled: bool,
+    divisor: u16,
+}
+
+impl Uart {
+    pub fn new(base_address: usize) -> Self {
+        Self {
+            base_address,
+            console: Console::default(),
+            echo: false,
+            line: None,
+            ier: 0,
+            thre_pending: Cell::new(false),
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            fifo_enabled: false,
+            divisor: 0,
+        }
+    }
+
+    /// Echoes transmitted bytes to the host stdout
+    pub fn with_echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    /// Connects the interrupt output of the UART to `line`
+    pub fn with_interrupt_line(mut self, line: u8) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    /// Returns a handle to the host side of the UART
+    pub fn console(&self) -> Console {
+        self.console.clone()
+    }
+
+    /// Returns the baud rate divisor programmed by the guest
+    pub fn divisor(&self) -> u16 {
+        self.divisor
+    }
+
+    /// Returns the line status register
+    pub fn line_status(&self) -> u8 {
+        let mut status = LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY;
+        if self.console.has_input() {
+            status |= LSR_DATA_READY;
+        }
+        status
+    }
+
+    /// Returns if an enabled interrupt condition is pending
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupt_id() & 1 == 0
+    }
+
+    /// Returns the interrupt identification, without the FIFO bits
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RECEIVED != 0 && self.console.has_input() {
+            0x04
+        } else if self.ier & IER_THR_EMPTY != 0 && self.thre_pending.get() {
+            0x02
+        } else {
+            0x01
+        }
+    }
+
+    fn dlab(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+
+    fn register(&self, address: usize) -> Result<usize, MemoryAccessError> {
+        address
+            .checked_sub(self.base_address)
+            .filter(|register| *register < REGISTERS)
+            .ok_or(MemoryAccessError::OutOfBounds { address, size: 1 })
+    }
+
+    fn read_register(&self, register: usize) -> u8 {
+        match register {
+            RBR_THR if self.dlab() => self.divisor as u8,
+            RBR_THR => self
+                .console
+                .buffers
+                .borrow_mut()
+                .input
+                .pop_front()
+                .unwrap_or(0),
+            IER if self.dlab() => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                // reporting THRE acknowledges it
+                if id == 0x02 {
+                    self.thre_pending.set(false);
+                }
+                if self.fifo_enabled {
+                    0xc0 | id
+                } else {
+                    id
+                }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => self.line_status(),
+            // report CTS and DSR, or the looped back RTS and DTR
+            MSR if self.mcr & MCR_LOOPBACK != 0 => (self.mcr & 0b11) << 4,
+            MSR => 0x30,
+            SCR => self.scr,
+            _ => unreachable!("invalid UART register {register}"),
+        }
+    }
+
+    fn write_register(&mut self, register: usize, value: u8) {
+        match register {
+            RBR_THR if self.dlab() => self.divisor = self.divisor & 0xff00 | value as u16,
+            RBR_THR => {
+                // writing the holding register acknowledges THRE
+                self.thre_pending.set(false);
+                if self.mcr & MCR_LOOPBACK != 0 {
+                    self.console.push_input(&[value]);
+                } else {
+                    self.transmit(value);
+                }
+                // the byte leaves the holding register immediately, which raises THRE again
+                self.thre_pending.set(true);
+            }
+            IER if self.dlab() => self.divisor = self.divisor & 0xff | (value as u16) << 8,
+            IER => {
+                // enabling the interrupt while the holding register is empty raises it
+                if value & IER_THR_EMPTY != 0 && self.ier & IER_THR_EMPTY == 0 {
+                    self.thre_pending.set(true);
+                }
+                self.ier = value & 0x0f;
+            }
+            IIR_FCR => {
+                self.fifo_enabled = value & 1 != 0;
+                // clear the receiver FIFO
+                if value & 0b10 != 0 {
+                    self.console.buffers.borrow_mut().input.clear();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1f,
+            SCR => self.scr = value,
+            // the status registers are read-only
+            LSR | MSR => {}
+            _ => unreachable!("invalid UART register {register}"),
+        }
+    }
+
+    fn transmit(&mut self, value: u8) {
+        self.console.buffers.borrow_mut().output.push(value);
+        if self.echo {
+            let mut stdout = std::io::stdout();
+            // the console is best effort, the guest cannot observe host errors
+            let _ = stdout.write_all(&[value]).and_then(|_| stdout.flush());
+        }
+    }
+}
+
+impl Addressable for Uart {
+    fn read_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        Ok(self.read_register(self.register(address)?))
+    }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, MemoryAccessError> {
+        // reads have side effects, nothing is read unless every register exists
+        self.register(address)?;
+        self.register(address + size.saturating_sub(1))?;
+        (address..address + size)
+            .map(|address| self.read_byte(address))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Cow::Owned)
+    }
+
+    fn write_byte(&mut self, address: usize, value: u8) -> Result<(), MemoryAccessError> {
+        let register = self.register(address)?;
+        self.write_register(register, value);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, address: usize, value: &[u8]) -> Result<(), MemoryAccessError> {
+        // nothing is written unless every register exists
+        self.register(address)?;
+        self.register(address + value.len().saturating_sub(1))?;
+        for (address, value) in (address..).zip(value) {
+            self.write_byte(address, *value)?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "UART"
+    }
+
+    fn start_address(&self) -> usize {
+        self.base_address
+    }
+
+    fn end_address(&self) -> usize {
+        self.base_address + REGISTERS
+    }
+
+    fn tick(&mut self, _tick: Tick, lines: &mut InterruptLines) {
+        if let Some(line) = self.line {
+            lines.set(line, self.interrupt_pending());
+        }
+    }
+
+    /// Resets the registers, the console keeps its buffered bytes
+    fn reset(&mut self) {
+        self.ier = 0;
+        self.thre_pending.set(false);
+        self.lcr = 0;
+        self.mcr = 0;
+        self.scr = 0;
+        self.fifo_enabled = false;
+        self.divisor = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_uart() {
+        let mut uart = Uart::new(0x3f8);
+        let console = uart.console();
+        assert_eq!(uart.read_byte(0x3fd).unwrap(), 0x60);
+        for byte in b"hi\n" {
+            uart.write_byte(0x3f8, *byte).unwrap();
+        }
+        assert_eq!(console.output(), b"hi\n");
+
+        console.push_input(b"ok");
+        assert_eq!(uart.read_byte(0x3fd).unwrap() & LSR_DATA_READY, 1);
+        assert_eq!(uart.read_byte(0x3f8).unwrap(), b'o');
+        assert_eq!(uart.read_byte(0x3f8).unwrap(), b'k');
+        assert_eq!(uart.read_byte(0x3fd).unwrap() & LSR_DATA_READY, 0);
+
+        // the divisor latch shadows the data and interrupt enable registers
+        uart.write_byte(0x3fb, LCR_DLAB | 0x03).unwrap();
+        uart.write_bytes(0x3f8, &[0x0c, 0x00]).unwrap();
+        uart.write_byte(0x3fb, 0x03).unwrap();
+        assert_eq!(uart.divisor(), 12);
+        assert_eq!(console.take_output(), b"hi\n");
+
+        uart.write_byte(0x3f9, IER_RECEIVED).unwrap();
+        assert_eq!(uart.read_byte(0x3fa).unwrap(), 0x01);
+        console.push_input(b"x");
+        assert!(uart.interrupt_pending());
+        assert_eq!(uart.read_byte(0x3fa).unwrap(), 0x04);
+        assert!(uart.read_byte(0x400).is_err());
+
+        // a read past the last register does not consume input
+        assert!(matches!(
+            uart.read_bytes(0x3f8, 9),
+            Err(MemoryAccessError::OutOfBounds { address: 0x400, .. })
+        ));
+        assert_eq!(*uart.read_bytes(0x3f8, 1).unwrap(), *b"x");
+    }
+
+    #[test]
+    fn test_uart_thre_acknowledge() {
+        let mut uart = Uart::new(0x3f8).with_interrupt_line(4);
+        let mut lines = InterruptLines::new();
+        let tick = Tick {
+            cycles: 1,
+            instructions: 1,
+        };
+
+        // enabling the interrupt raises it, reading the IIR acknowledges it
+        uart.write_byte(0x3f9, IER_THR_EMPTY).unwrap();
+        uart.tick(tick, &mut lines);
+        assert!(lines.is_raised(4));
+        assert_eq!(uart.read_byte(0x3fa).unwrap(), 0x02);
+        assert_eq!(uart.read_byte(0x3fa).unwrap(), 0x01);
+        uart.tick(tick, &mut lines);
+        assert!(!lines.is_raised(4));
+
+        // transmitting a byte empties the holding register again
+        uart.write_byte(0x3f8, b'a').unwrap();
+        uart.tick(tick, &mut lines);
+        assert!(lines.is_raised(4));
+        assert_eq!(uart.read_byte(0x3fa).unwrap(), 0x02);
+        assert!(!uart.interrupt_pending());
+    }
+}
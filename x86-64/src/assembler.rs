@@ -0,0 +1,884 @@
+//! A small Intel syntax assembler
+//!
+//! Supports the instructions known to the [encoder](crate::encoder), labels,
+//! the `db`, `dw`, `dd` and `dq` data directives and `org`. Comments start
+//! with `;` or `#`.
+//!
+//! ```text
+//!     org 0x1000
+//! start:
+//!     mov rax, qword ptr [rip + value]
+//!     call double
+//!     hlt
+//! double:
+//!     add rax, rax
+//!     ret
+//! value: dq 21
+//! ```
+
+use std::collections::{BTreeMap, HashSet};
+
+use cpu::device::DRAM;
+use cpu::{Addressable, MemoryAccessError};
+use thiserror::Error;
+
+use crate::disassembler::{condition_name, register_name, SymbolTable};
+use crate::encoder::{encode, EncodeError};
+use crate::instruction::{
+    Addressing, Condition, Dest, Instr, Memory, OperandSize, Reg, Segment, Src,
+};
+
+/// The mnemonics without a condition code
+const MNEMONICS: [&str; 21] = [
+    "ret", "nop", "hlt", "push", "pop", "jmp", "call", "inc", "dec", "not", "neg", "idiv", "mov",
+    "add", "sub", "and", "or", "xor", "cmp", "test", "imul",
+];
+
+/// Branch displacements shrink and grow with the layout, give up if it does
+/// not settle after this many passes
+const MAX_PASSES: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum AssembleError {
+    /// The line could not be parsed
+    #[error("Line {line}: {message}")]
+    Syntax { line: usize, message: String },
+
+    #[error("Line {line}: undefined symbol `{name}`")]
+    UndefinedSymbol { line: usize, name: String },
+
+    #[error("Line {line}: the symbol `{name}` is already defined")]
+    DuplicateSymbol { line: usize, name: String },
+
+    /// The instruction has no encoding
+    #[error("Line {line}: {source}")]
+    Encode { line: usize, source: EncodeError },
+
+    #[error("The program layout did not settle after {0} passes")]
+    Unstable(usize),
+}
+
+/// An assembled program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// The address of the first byte
+    pub origin: u64,
+    pub bytes: Vec<u8>,
+    /// The address of every label
+    pub symbols: BTreeMap<String, u64>,
+}
+
+impl Program {
+    /// Returns the address of the label `name`
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Returns the labels indexed by address
+    pub fn symbols_by_address(&self) -> BTreeMap<u64, String> {
+        self.symbols
+            .iter()
+            .map(|(name, address)| (*address, name.clone()))
+            .collect()
+    }
+
+    /// Returns the labels as a symbol table for the disassembler
+    ///
+    /// Every label covers the bytes up to the next label, the last one up to
+    /// the end of the program.
+    pub fn symbol_table(&self) -> SymbolTable {
+        let by_address = self.symbols_by_address();
+        let end = self.origin + self.bytes.len() as u64;
+        let mut table = SymbolTable::new();
+        let mut labels = by_address.iter().peekable();
+        while let Some((start, name)) = labels.next() {
+            let next = labels.peek().map_or(end, |(next, _)| **next);
+            table.insert(name.clone(), *start, next);
+        }
+        table
+    }
+
+    /// Allocates the memory of the program in `dram` and copies it there
+    pub fn load(&self, dram: &mut DRAM) -> Result<(), MemoryAccessError> {
+        dram.map(self.origin as usize, self.bytes.len())?;
+        dram.write_bytes(self.origin as usize, &self.bytes)
+    }
+}
+
+/// Assembles `source` into a program
+pub fn assemble(source: &str) -> Result<Program, AssembleError> {
+    let statements = parse(source)?;
+
+    let mut labels = HashSet::new();
+    for (line, statement) in &statements {
+        if let Statement::Label(name) = statement {
+            if !labels.insert(name.as_str()) {
+                return Err(AssembleError::DuplicateSymbol {
+                    line: *line,
+                    name: name.clone(),
+                });
+            }
+        }
+    }
+
+    let origin = statements
+        .iter()
+        .map(|(_, statement)| statement)
+        .take_while(|statement| !matches!(statement, Statement::Data(..) | Statement::Instr(..)))
+        .find_map(|statement| match statement {
+            Statement::Org(address) => Some(*address),
+            _ => None,
+        })
+        .unwrap_or(0);
+
+    // lay the program out until no instruction changes its length
+    let mut sizes = vec![0; statements.len()];
+    for _ in 0..MAX_PASSES {
+        let symbols = layout(&statements, origin, &sizes);
+        let (bytes, new_sizes) = emit(&statements, origin, &symbols)?;
+        if new_sizes == sizes {
+            return Ok(Program {
+                origin,
+                bytes,
+                symbols,
+            });
+        }
+        sizes = new_sizes;
+    }
+    Err(AssembleError::Unstable(MAX_PASSES))
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+    Label(String),
+    Org(u64),
+    Data(OperandSize, Vec<DataItem>),
+    Instr(String, Vec<Operand>),
+}
+
+#[derive(Debug, Clone)]
+enum DataItem {
+    Value(Expr),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Reg(Reg),
+    Imm(Expr),
+    Mem(MemOperand),
+}
+
+#[derive(Debug, Clone)]
+struct MemOperand {
+    size: Option<OperandSize>,
+    segment: Option<Segment>,
+    rip: bool,
+    base: Option<u8>,
+    index: Option<(u8, u8)>,
+    displacement: Expr,
+}
+
+/// A sum of numbers and symbols
+#[derive(Debug, Clone, Default)]
+struct Expr(Vec<(bool, Atom)>);
+
+#[derive(Debug, Clone)]
+enum Atom {
+    Number(u64),
+    Symbol(String),
+}
+
+impl Expr {
+    fn has_symbol(&self) -> bool {
+        self.0
+            .iter()
+            .any(|(_, atom)| matches!(atom, Atom::Symbol(_)))
+    }
+
+    fn evaluate(&self, line: usize, symbols: &BTreeMap<String, u64>) -> Result<u64, AssembleError> {
+        self.0.iter().try_fold(0u64, |sum, (negative, atom)| {
+            let value = match atom {
+                Atom::Number(value) => *value,
+                Atom::Symbol(name) => {
+                    *symbols
+                        .get(name)
+                        .ok_or_else(|| AssembleError::UndefinedSymbol {
+                            line,
+                            name: name.clone(),
+                        })?
+                }
+            };
+            Ok(if *negative {
+                sum.wrapping_sub(value)
+            } else {
+                sum.wrapping_add(value)
+            })
+        })
+    }
+}
+
+/// Computes the address of every label from the statement sizes of the previous pass
+fn layout(
+    statements: &[(usize, Statement)],
+    origin: u64,
+    sizes: &[usize],
+) -> BTreeMap<String, u64> {
+    let mut symbols = BTreeMap::new();
+    let mut address = origin;
+    for ((_, statement), size) in statements.iter().zip(sizes) {
+        match statement {
+            Statement::Label(name) => {
+                symbols.insert(name.clone(), address);
+            }
+            // sizes of the previous pass may overshoot, `emit` reports a real overlap
+            Statement::Org(target) => address = address.max(*target),
+            _ => address += *size as u64,
+        }
+    }
+    symbols
+}
+
+/// Checks that `org` does not move backwards
+fn org(line: usize, address: u64, target: u64) -> Result<u64, AssembleError> {
+    if target < address {
+        return Err(syntax(
+            line,
+            format!("org {target:#x} is below the current address {address:#x}"),
+        ));
+    }
+    Ok(target)
+}
+
+/// Produces the bytes of the program and the size of every statement
+fn emit(
+    statements: &[(usize, Statement)],
+    origin: u64,
+    symbols: &BTreeMap<String, u64>,
+) -> Result<(Vec<u8>, Vec<usize>), AssembleError> {
+    let mut bytes = Vec::new();
+    let mut sizes = Vec::with_capacity(statements.len());
+    for (line, statement) in statements {
+        let line = *line;
+        let address = origin + bytes.len() as u64;
+        let start = bytes.len();
+        match statement {
+            Statement::Label(_) => {}
+            Statement::Org(target) => {
+                let target = org(line, address, *target)?;
+                bytes.resize((target - origin) as usize, 0);
+            }
+            Statement::Data(size, items) => {
+                for item in items {
+                    match item {
+                        DataItem::Bytes(data) => bytes.extend_from_slice(data),
+                        DataItem::Value(expr) => {
+                            let value = expr.evaluate(line, symbols)?;
+                            let truncated = value & size.mask();
+                            if truncated != value && size.sign_extend(truncated) as u64 != value {
+                                return Err(syntax(
+                                    line,
+                                    format!("{value:#x} does not fit in {} bits", size.bits()),
+                                ));
+                            }
+                            bytes.extend_from_slice(&truncated.to_le_bytes()[..size.bytes()]);
+                        }
+                    }
+                }
+            }
+            Statement::Instr(mnemonic, operands) => {
+                let build = |next_rip| {
+                    let instr = instruction(line, mnemonic, operands, symbols, next_rip)?;
+                    encode(&instr, address).map_err(|source| AssembleError::Encode { line, source })
+                };
+                // RIP-relative displacements are always 32 bits wide, the
+                // length does not depend on the address of the next instruction
+                let length = build(address)?.len();
+                bytes.extend(build(address + length as u64)?);
+            }
+        }
+        sizes.push(bytes.len() - start);
+    }
+    Ok((bytes, sizes))
+}
+
+fn syntax(line: usize, message: impl Into<String>) -> AssembleError {
+    AssembleError::Syntax {
+        line,
+        message: message.into(),
+    }
+}
+
+fn parse(source: &str) -> Result<Vec<(usize, Statement)>, AssembleError> {
+    let mut statements = Vec::new();
+    for (number, text) in source.lines().enumerate() {
+        let line = number + 1;
+        let mut text = strip_comment(text).trim();
+
+        // any number of labels may precede the statement
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_identifier(label) || parse_segment(label).is_some() {
+                break;
+            }
+            statements.push((line, Statement::Label(label.to_string())));
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let mnemonic = mnemonic.to_ascii_lowercase();
+        let rest = rest.trim();
+        let statement = match mnemonic.as_str() {
+            "org" => Statement::Org(
+                parse_number(rest)
+                    .ok_or_else(|| syntax(line, format!("invalid address `{rest}`")))?,
+            ),
+            "db" | "dw" | "dd" | "dq" => {
+                let size = match mnemonic.as_str() {
+                    "db" => OperandSize::Byte,
+                    "dw" => OperandSize::Word,
+                    "dd" => OperandSize::Dword,
+                    _ => OperandSize::Qword,
+                };
+                let items = split_operands(rest)
+                    .into_iter()
+                    .map(|item| parse_data(item, size).map_err(|message| syntax(line, message)))
+                    .collect::<Result<_, _>>()?;
+                Statement::Data(size, items)
+            }
+            _ => {
+                let operands = split_operands(rest)
+                    .into_iter()
+                    .map(|operand| parse_operand(operand).map_err(|message| syntax(line, message)))
+                    .collect::<Result<_, _>>()?;
+                Statement::Instr(mnemonic, operands)
+            }
+        };
+        statements.push((line, statement));
+    }
+    Ok(statements)
+}
+
+/// Removes a comment, ignoring comment characters in quotes
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, ';' | '#') => return &text[..i],
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Splits a comma separated list, ignoring commas in quotes and brackets
+fn split_operands(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let (mut quote, mut depth, mut start) = (None, 0, 0);
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    let text = text.replace('_', "");
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        u64::from_str_radix(binary, 2).ok()
+    } else if let Some(c) = text.strip_prefix('\'').and_then(|c| c.strip_suffix('\'')) {
+        let mut chars = c.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii() => Some(c as u64),
+            _ => None,
+        }
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn parse_register(text: &str) -> Option<Reg> {
+    let text = text.to_ascii_lowercase();
+    let sizes = [
+        OperandSize::Byte,
+        OperandSize::Word,
+        OperandSize::Dword,
+        OperandSize::Qword,
+    ];
+    sizes
+        .into_iter()
+        .flat_map(|size| (0..16).map(move |index| Reg::Gpr(index, size)))
+        .chain((0..4).map(Reg::HighByte))
+        .chain(
+            [
+                Segment::Es,
+                Segment::Cs,
+                Segment::Ss,
+                Segment::Ds,
+                Segment::Fs,
+                Segment::Gs,
+            ]
+            .map(Reg::Segment),
+        )
+        .find(|reg| register_name(*reg) == text)
+}
+
+fn parse_segment(text: &str) -> Option<Segment> {
+    match text.to_ascii_lowercase().as_str() {
+        "es" => Some(Segment::Es),
+        "cs" => Some(Segment::Cs),
+        "ss" => Some(Segment::Ss),
+        "ds" => Some(Segment::Ds),
+        "fs" => Some(Segment::Fs),
+        "gs" => Some(Segment::Gs),
+        _ => None,
+    }
+}
+
+fn parse_condition(text: &str) -> Option<Condition> {
+    const ALIASES: [(&str, Condition); 14] = [
+        ("z", Condition::E),
+        ("nz", Condition::Ne),
+        ("c", Condition::B),
+        ("nae", Condition::B),
+        ("nc", Condition::Ae),
+        ("nb", Condition::Ae),
+        ("na", Condition::Be),
+        ("nbe", Condition::A),
+        ("pe", Condition::P),
+        ("po", Condition::Np),
+        ("nge", Condition::L),
+        ("nl", Condition::Ge),
+        ("ng", Condition::Le),
+        ("nle", Condition::G),
+    ];
+    (0..16)
+        .map(Condition::from_code)
+        .find(|condition| condition_name(*condition) == text)
+        .or_else(|| {
+            ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == text)
+                .map(|(_, condition)| *condition)
+        })
+}
+
+/// Parses `label`, `-8`, `label + 4` and similar sums
+fn parse_expr(text: &str) -> Result<Expr, String> {
+    let mut expr = Expr::default();
+    for (negative, term) in split_terms(text)? {
+        expr.0.push((negative, parse_atom(term)?));
+    }
+    Ok(expr)
+}
+
+fn parse_atom(text: &str) -> Result<Atom, String> {
+    if let Some(value) = parse_number(text) {
+        Ok(Atom::Number(value))
+    } else if is_identifier(text) {
+        Ok(Atom::Symbol(text.to_string()))
+    } else {
+        Err(format!("invalid expression `{text}`"))
+    }
+}
+
+/// Splits a sum into its terms and their signs
+fn split_terms(text: &str) -> Result<Vec<(bool, &str)>, String> {
+    let mut terms = Vec::new();
+    let mut negative = false;
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            '+' | '-' if !quoted => {
+                let term = text[start..i].trim();
+                if !term.is_empty() {
+                    terms.push((negative, term));
+                } else if !terms.is_empty() || c == '+' && i != 0 {
+                    return Err(format!("invalid expression `{text}`"));
+                }
+                negative = c == '-';
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    let term = text[start..].trim();
+    if term.is_empty() {
+        return Err(format!("invalid expression `{text}`"));
+    }
+    terms.push((negative, term));
+    Ok(terms)
+}
+
+fn parse_data(text: &str, size: OperandSize) -> Result<DataItem, String> {
+    if let Some(string) = text.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        if size != OperandSize::Byte {
+            return Err("strings are only allowed in db".to_string());
+        }
+        return Ok(DataItem::Bytes(string.as_bytes().to_vec()));
+    }
+    parse_expr(text).map(DataItem::Value)
+}
+
+fn parse_operand(text: &str) -> Result<Operand, String> {
+    if let Some(reg) = parse_register(text) {
+        return Ok(Operand::Reg(reg));
+    }
+
+    let lower = text.to_ascii_lowercase();
+    let sizes = [
+        ("byte", OperandSize::Byte),
+        ("word", OperandSize::Word),
+        ("dword", OperandSize::Dword),
+        ("qword", OperandSize::Qword),
+    ];
+    let mut size = None;
+    let mut rest = text;
+    for (keyword, operand_size) in sizes {
+        if let Some(after) = lower.strip_prefix(keyword) {
+            if after.starts_with(char::is_whitespace) || after.starts_with('[') {
+                size = Some(operand_size);
+                rest = text[keyword.len()..].trim_start();
+                if rest.to_ascii_lowercase().starts_with("ptr") {
+                    rest = rest[3..].trim_start();
+                }
+                break;
+            }
+        }
+    }
+
+    let mut segment = None;
+    if let Some((prefix, after)) = rest.split_once(':') {
+        if let Some(s) = parse_segment(prefix.trim()) {
+            segment = Some(s);
+            rest = after.trim();
+        }
+    }
+
+    let Some(inner) = rest.strip_prefix('[').and_then(|r| r.strip_suffix(']')) else {
+        if size.is_some() || segment.is_some() {
+            return Err(format!("invalid memory operand `{text}`"));
+        }
+        return parse_expr(text).map(Operand::Imm);
+    };
+
+    let mut inner = inner.trim();
+    if let Some((prefix, after)) = inner.split_once(':') {
+        if let Some(s) = parse_segment(prefix.trim()) {
+            segment = Some(s);
+            inner = after.trim();
+        }
+    }
+
+    let mut memory = MemOperand {
+        size,
+        segment,
+        rip: false,
+        base: None,
+        index: None,
+        displacement: Expr::default(),
+    };
+    for (negative, term) in split_terms(inner)? {
+        let register = |name: &str| match parse_register(name.trim()) {
+            Some(Reg::Gpr(index, OperandSize::Qword)) => Ok(index),
+            _ => Err(format!("invalid address register `{name}`")),
+        };
+        if term.eq_ignore_ascii_case("rip") && !negative {
+            memory.rip = true;
+        } else if let Some((a, b)) = term.split_once('*') {
+            let (reg, scale) = match parse_number(b.trim()) {
+                Some(scale) => (a, scale),
+                None => (b, parse_number(a.trim()).unwrap_or(0)),
+            };
+            if negative || memory.index.is_some() || ![1, 2, 4, 8].contains(&scale) {
+                return Err(format!("invalid index `{term}`"));
+            }
+            memory.index = Some((register(reg)?, scale as u8));
+        } else if parse_register(term).is_some() {
+            let reg = register(term)?;
+            if negative {
+                return Err(format!("invalid address `{inner}`"));
+            }
+            if memory.base.is_none() {
+                memory.base = Some(reg);
+            } else if memory.index.is_none() {
+                memory.index = Some((reg, 1));
+            } else {
+                return Err(format!("too many registers in `{inner}`"));
+            }
+        } else {
+            memory.displacement.0.push((negative, parse_atom(term)?));
+        }
+    }
+    if memory.rip && (memory.base.is_some() || memory.index.is_some()) {
+        return Err(format!("invalid address `{inner}`"));
+    }
+    Ok(Operand::Mem(memory))
+}
+
+/// Builds the instruction of a statement
+///
+/// `next_rip` is the address of the following instruction, used for
+/// RIP-relative operands referencing a label.
+fn instruction(
+    line: usize,
+    mnemonic: &str,
+    operands: &[Operand],
+    symbols: &BTreeMap<String, u64>,
+    next_rip: u64,
+) -> Result<Instr, AssembleError> {
+    let invalid = || syntax(line, format!("invalid operands for `{mnemonic}`"));
+
+    // the operand size is given by a register or an explicit memory size
+    let size = operands.iter().find_map(|operand| match operand {
+        Operand::Reg(reg) => Some(reg.size()),
+        Operand::Mem(mem) => mem.size,
+        Operand::Imm(_) => None,
+    });
+
+    let src = |operand: &Operand, size: Option<OperandSize>| -> Result<Src, AssembleError> {
+        match operand {
+            Operand::Reg(reg) => Ok(Src::Reg(*reg)),
+            Operand::Imm(expr) => Ok(Src::Imm(expr.evaluate(line, symbols)?)),
+            Operand::Mem(mem) => {
+                let size = mem.size.or(size).ok_or_else(|| {
+                    syntax(
+                        line,
+                        format!("operand size of `{mnemonic}` is not specified"),
+                    )
+                })?;
+                let displacement = mem.displacement.evaluate(line, symbols)? as i64;
+                let addressing = if mem.rip && mem.displacement.has_symbol() {
+                    Addressing::RipRelative(displacement.wrapping_sub(next_rip as i64))
+                } else if mem.rip {
+                    Addressing::RipRelative(displacement)
+                } else {
+                    Addressing::new(mem.base, mem.index, displacement)
+                };
+                Ok(Src::Mem(
+                    Memory::new(addressing, size).with_segment(mem.segment),
+                ))
+            }
+        }
+    };
+    let dest = |operand: &Operand, size: Option<OperandSize>| match src(operand, size)? {
+        Src::Reg(reg) => Ok(Dest::Reg(reg)),
+        Src::Mem(mem) => Ok(Dest::Mem(mem)),
+        Src::Imm(_) => Err(invalid()),
+    };
+
+    let qword = Some(OperandSize::Qword);
+    let instr = match (mnemonic, operands) {
+        ("ret", []) => Instr::Ret,
+        ("nop", []) => Instr::Nop,
+        ("hlt", []) => Instr::Hlt,
+
+        ("push", [a]) => Instr::Push(src(a, qword)?),
+        ("pop", [a]) => Instr::Pop(dest(a, qword)?),
+        ("jmp", [a]) => Instr::Jmp(src(a, qword)?),
+        ("call", [a]) => Instr::Call(src(a, qword)?),
+
+        ("inc", [a]) => Instr::Inc(dest(a, size)?),
+        ("dec", [a]) => Instr::Dec(dest(a, size)?),
+        ("not", [a]) => Instr::Not(dest(a, size)?),
+        ("neg", [a]) => Instr::Neg(dest(a, size)?),
+        ("idiv", [a]) => Instr::IDiv(src(a, size)?),
+
+        ("mov", [a, b]) => Instr::Mov(dest(a, size)?, src(b, size)?),
+        ("add", [a, b]) => Instr::Add(dest(a, size)?, src(b, size)?),
+        ("sub", [a, b]) => Instr::Sub(dest(a, size)?, src(b, size)?),
+        ("and", [a, b]) => Instr::And(dest(a, size)?, src(b, size)?),
+        ("or", [a, b]) => Instr::Or(dest(a, size)?, src(b, size)?),
+        ("xor", [a, b]) => Instr::Xor(dest(a, size)?, src(b, size)?),
+        ("cmp", [a, b]) => Instr::Cmp(dest(a, size)?, src(b, size)?),
+        ("test", [a, b]) => Instr::Test(dest(a, size)?, src(b, size)?),
+        ("imul", [a, b]) => Instr::IMul(dest(a, size)?, src(b, size)?),
+
+        (mnemonic, operands) => {
+            let conditional =
+                |prefix: &str| mnemonic.strip_prefix(prefix).and_then(parse_condition);
+            match (
+                conditional("j"),
+                conditional("set"),
+                conditional("cmov"),
+                operands,
+            ) {
+                (Some(condition), _, _, [a]) => Instr::Jcc(condition, src(a, qword)?),
+                (_, Some(condition), _, [a]) => {
+                    Instr::Setcc(condition, dest(a, Some(OperandSize::Byte))?)
+                }
+                (_, _, Some(condition), [a, b]) => {
+                    Instr::Cmovcc(condition, dest(a, size)?, src(b, size)?)
+                }
+                (None, None, None, _) if !MNEMONICS.contains(&mnemonic) => {
+                    return Err(syntax(line, format!("unknown instruction `{mnemonic}`")))
+                }
+                _ => return Err(invalid()),
+            }
+        }
+    };
+    Ok(instr)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoder::decode;
+    use crate::disassembler::{Disassembler, SymbolResolver, Syntax};
+    use crate::paging::PagingMode;
+    use crate::Cpu;
+    use cpu::{Cpu as _, StopReason};
+
+    #[test]
+    fn test_assemble_and_run() {
+        let program = assemble(
+            r#"
+                org 0x1000
+            start:
+                mov rax, qword ptr [rip + value]   ; 21
+                mov rcx, 3
+            again: call double
+                dec rcx
+                jnz again
+                cmp byte ptr [message + 1], 'i'
+                sete bl
+                hlt
+            double:
+                add rax, rax
+                ret
+            value: dq 21
+            message: db "hi", 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(program.origin, 0x1000);
+        assert_eq!(program.symbol("start"), Some(0x1000));
+        assert_eq!(
+            &program.bytes[..7],
+            [0x48, 0x8b, 0x05, 0x21, 0x00, 0x00, 0x00]
+        );
+        let message = program.symbol("message").unwrap() as usize - 0x1000;
+        assert_eq!(&program.bytes[message..], b"hi\0");
+
+        let mut dram = DRAM::new(0, 1 << 16);
+        program.load(&mut dram).unwrap();
+        dram.alloc(0x8000, 0x1000).unwrap();
+        let mut cpu = Cpu::new(PagingMode::Long);
+        cpu.add_device(Box::new(dram)).unwrap();
+        cpu.registers_mut().write_rip(0x1000);
+        cpu.registers_mut().write_rsp(0x9000);
+        assert_eq!(cpu.run(), StopReason::Halted);
+        assert_eq!(cpu.registers().rax(), 21 * 8);
+        assert_eq!(cpu.registers().rbx(), 1);
+    }
+
+    #[test]
+    fn test_layout() {
+        // the forward branch needs a 32-bit displacement, the loop does not
+        let program = assemble(
+            "
+            top: jmp bottom
+                 nop
+                 org 0x200
+            bottom: jmp bottom
+                 dw -1, bottom
+            ",
+        )
+        .unwrap();
+        assert_eq!(program.bytes[..5], [0xe9, 0xfb, 0x01, 0x00, 0x00]);
+        assert_eq!(program.bytes[5], 0x90);
+        assert!(program.bytes[6..0x200].iter().all(|b| *b == 0));
+        assert_eq!(program.bytes[0x200..], [0xeb, 0xfe, 0xff, 0xff, 0x00, 0x02]);
+        assert_eq!(
+            program.symbols_by_address(),
+            BTreeMap::from([(0, "top".to_string()), (0x200, "bottom".to_string())])
+        );
+        let symbols = program.symbol_table();
+        assert_eq!(symbols.resolve(0x1ff), Some(("top", 0x1ff)));
+        assert_eq!(symbols.resolve(0x205), Some(("bottom", 5)));
+        assert_eq!(symbols.resolve(0x206), None);
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let source = [
+            "add r9, qword ptr [rsi + r10*4 + 0x10]",
+            "cmp dword ptr [rbp - 0x8], 0xffffffff",
+            "mov ah, byte ptr fs:[0x28]",
+            "setle dil",
+            "call qword ptr [rax]",
+            "imul r11w, word ptr [rbx + rcx]",
+            "push 0xffffffffffffff80",
+        ];
+        let program = assemble(&source.join("\n")).unwrap();
+        // the push immediate still fits the sign-extended imm8 form
+        assert_eq!(program.bytes[program.bytes.len() - 2..], [0x6a, 0x80]);
+
+        let mut dram = DRAM::new(0, 1 << 16);
+        program.load(&mut dram).unwrap();
+        let mut address = 0;
+        for line in source {
+            let (instr, length) = decode(&dram, address).unwrap();
+            let text = Disassembler::new(Syntax::Intel).format(&instr, None);
+            assert_eq!(text, line);
+            address += length as u64;
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            assemble("jmp nowhere"),
+            Err(AssembleError::UndefinedSymbol { line: 1, .. })
+        ));
+        assert!(matches!(
+            assemble("a: nop\na: nop"),
+            Err(AssembleError::DuplicateSymbol { line: 2, .. })
+        ));
+        assert!(matches!(
+            assemble("nop\nfrobnicate rax"),
+            Err(AssembleError::Syntax { line: 2, .. })
+        ));
+        assert!(matches!(
+            assemble("inc [rax]"),
+            Err(AssembleError::Syntax { line: 1, .. })
+        ));
+        assert!(matches!(
+            assemble("mov ah, sil"),
+            Err(AssembleError::Encode {
+                source: EncodeError::InvalidOperands(_),
+                ..
+            })
+        ));
+    }
+}
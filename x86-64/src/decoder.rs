@@ -0,0 +1,910 @@
+use cpu::{Addressable, MemoryAccessError};
+use thiserror::Error;
+
+use crate::instruction::{
+    Addressing, Condition, Dest, Instr, Memory, OperandSize, Reg, Segment, Src,
+};
+
+/// The architectural limit on the length of a single instruction
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    /// The instruction bytes could not be fetched
+    #[error(transparent)]
+    Memory(#[from] MemoryAccessError),
+
+    /// The opcode is invalid or not supported by the decoder
+    #[error("Unsupported opcode {opcode:#x} at address {address:#x}")]
+    UnsupportedOpcode { address: u64, opcode: u32 },
+
+    /// The instruction is longer than 15 bytes
+    #[error("The instruction at address {address:#x} exceeds 15 bytes")]
+    TooLong { address: u64 },
+}
+
+/// The default operand and address size of the code segment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CodeSize {
+    /// Real mode and 16-bit protected mode code
+    Bits16,
+    /// 32-bit protected mode code
+    Bits32,
+    /// 64-bit long mode code
+    #[default]
+    Bits64,
+}
+
+/// Decodes the 64-bit instruction located at `rip`
+///
+/// Returns the instruction together with its encoded length in bytes.
+/// Relative branch targets are resolved to absolute addresses.
+pub fn decode<A: Addressable + ?Sized>(mem: &A, rip: u64) -> Result<(Instr, usize), DecodeError> {
+    decode_sized(mem, rip, CodeSize::Bits64)
+}
+
+/// Decodes the instruction located at `rip` in code of the given size
+///
+/// Outside of 64-bit code there is no REX prefix, 0x40 to 0x4f encode INC
+/// and DEC, and branch targets wrap around at the width of the instruction
+/// pointer.
+pub fn decode_sized<A: Addressable + ?Sized>(
+    mem: &A,
+    rip: u64,
+    code_size: CodeSize,
+) -> Result<(Instr, usize), DecodeError> {
+    let mut decoder = Decoder {
+        mem,
+        start: rip,
+        offset: 0,
+        code_size,
+        prefixes: Prefixes::default(),
+        rex: Rex::default(),
+    };
+    let instr = decoder.decode()?;
+    Ok((instr, decoder.offset))
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Prefixes {
+    operand_size: bool,
+    address_size: bool,
+    segment: Option<Segment>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Rex {
+    present: bool,
+    w: bool,
+    r: bool,
+    x: bool,
+    b: bool,
+}
+
+impl Rex {
+    fn from_byte(byte: u8) -> Self {
+        Self {
+            present: true,
+            w: byte & 0b1000 != 0,
+            r: byte & 0b0100 != 0,
+            x: byte & 0b0010 != 0,
+            b: byte & 0b0001 != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ModRM {
+    mode: u8,
+    reg: u8,
+    rm: u8,
+}
+
+struct Decoder<'a, A: Addressable + ?Sized> {
+    mem: &'a A,
+    start: u64,
+    offset: usize,
+    code_size: CodeSize,
+    prefixes: Prefixes,
+    rex: Rex,
+}
+
+impl<A: Addressable + ?Sized> Decoder<'_, A> {
+    fn address(&self) -> u64 {
+        self.start.wrapping_add(self.offset as u64)
+    }
+
+    fn next_u8(&mut self) -> Result<u8, DecodeError> {
+        if self.offset >= MAX_INSTRUCTION_LENGTH {
+            return Err(DecodeError::TooLong {
+                address: self.start,
+            });
+        }
+        let byte = self.mem.fetch_byte(self.address() as usize)?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn next_u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes([self.next_u8()?, self.next_u8()?]))
+    }
+
+    fn next_u32(&mut self) -> Result<u32, DecodeError> {
+        let mut bytes = [0; 4];
+        for byte in bytes.iter_mut() {
+            *byte = self.next_u8()?;
+        }
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn next_u64(&mut self) -> Result<u64, DecodeError> {
+        let mut bytes = [0; 8];
+        for byte in bytes.iter_mut() {
+            *byte = self.next_u8()?;
+        }
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Reads a sign-extended 8-bit immediate
+    fn imm8(&mut self) -> Result<u64, DecodeError> {
+        Ok(self.next_u8()? as i8 as i64 as u64)
+    }
+
+    /// Reads a sign-extended 32-bit immediate
+    fn imm32(&mut self) -> Result<u64, DecodeError> {
+        Ok(self.next_u32()? as i32 as i64 as u64)
+    }
+
+    /// Reads a sign-extended immediate of the given operand size, at most 32 bits wide
+    fn imm(&mut self, size: OperandSize) -> Result<u64, DecodeError> {
+        match size {
+            OperandSize::Byte => self.imm8(),
+            OperandSize::Word => Ok(self.next_u16()? as i16 as i64 as u64),
+            OperandSize::Dword | OperandSize::Qword => self.imm32(),
+        }
+    }
+
+    /// Reads a relative branch displacement and resolves it against the next instruction
+    fn rel8(&mut self) -> Result<Src, DecodeError> {
+        let rel = self.imm8()?;
+        Ok(self.target(rel))
+    }
+
+    /// Reads a 16 or 32-bit displacement, depending on the operand size
+    fn rel(&mut self) -> Result<Src, DecodeError> {
+        let rel = match self.near_branch_size() {
+            OperandSize::Word => self.imm(OperandSize::Word)?,
+            _ => self.imm32()?,
+        };
+        Ok(self.target(rel))
+    }
+
+    /// Resolves a branch displacement, wrapping around at the width of the instruction pointer
+    fn target(&self, rel: u64) -> Src {
+        Src::Imm(self.address().wrapping_add(rel) & self.near_branch_size().mask())
+    }
+
+    /// The effective operand size of a non-byte instruction
+    fn operand_size(&self) -> OperandSize {
+        // the prefix selects the size that is not the default of the code segment
+        let word = (self.code_size == CodeSize::Bits16) != self.prefixes.operand_size;
+        if self.rex.w {
+            OperandSize::Qword
+        } else if word {
+            OperandSize::Word
+        } else {
+            OperandSize::Dword
+        }
+    }
+
+    /// The effective operand size of an instruction defaulting to 64 bits (PUSH, POP)
+    ///
+    /// Outside of 64-bit code the stack follows the operand size.
+    fn stack_operand_size(&self) -> OperandSize {
+        match self.code_size {
+            CodeSize::Bits64 if self.prefixes.operand_size && !self.rex.w => OperandSize::Word,
+            CodeSize::Bits64 => OperandSize::Qword,
+            _ => self.operand_size(),
+        }
+    }
+
+    /// The operand size of near branches, which is always 64 bits in 64-bit code
+    fn near_branch_size(&self) -> OperandSize {
+        match self.code_size {
+            CodeSize::Bits64 => OperandSize::Qword,
+            _ => self.operand_size(),
+        }
+    }
+
+    /// The effective address size, selected by the code size and the 0x67 prefix
+    fn address_size(&self) -> OperandSize {
+        match (self.code_size, self.prefixes.address_size) {
+            (CodeSize::Bits16, false) | (CodeSize::Bits32, true) => OperandSize::Word,
+            (CodeSize::Bits16, true) | (CodeSize::Bits32, false) | (CodeSize::Bits64, true) => {
+                OperandSize::Dword
+            }
+            (CodeSize::Bits64, false) => OperandSize::Qword,
+        }
+    }
+
+    /// The operand size selected by the low opcode bit: byte if clear
+    fn operand_size_w(&self, opcode: u8) -> OperandSize {
+        if opcode & 1 == 0 {
+            OperandSize::Byte
+        } else {
+            self.operand_size()
+        }
+    }
+
+    /// Builds a register operand from its encoding index
+    ///
+    /// Without a REX prefix the byte registers 4 to 7 are AH, CH, DH and BH.
+    fn register(&self, index: u8, size: OperandSize) -> Reg {
+        if size == OperandSize::Byte && !self.rex.present && (4..8).contains(&index) {
+            Reg::HighByte(index - 4)
+        } else {
+            Reg::Gpr(index, size)
+        }
+    }
+
+    /// The register encoded in the low three bits of the opcode
+    fn opcode_reg(&self, opcode: u8, size: OperandSize) -> Reg {
+        self.register((opcode & 0b111) | ((self.rex.b as u8) << 3), size)
+    }
+
+    fn modrm(&mut self) -> Result<ModRM, DecodeError> {
+        let byte = self.next_u8()?;
+        Ok(ModRM {
+            mode: byte >> 6,
+            reg: (byte >> 3) & 0b111,
+            rm: byte & 0b111,
+        })
+    }
+
+    /// The register selected by the reg field of the ModRM byte
+    fn reg(&self, modrm: ModRM, size: OperandSize) -> Reg {
+        self.register(modrm.reg | ((self.rex.r as u8) << 3), size)
+    }
+
+    /// Decodes the r/m operand of the ModRM byte
+    fn rm(&mut self, modrm: ModRM, size: OperandSize) -> Result<Dest, DecodeError> {
+        if modrm.mode == 0b11 {
+            return Ok(Dest::Reg(
+                self.register(modrm.rm | ((self.rex.b as u8) << 3), size),
+            ));
+        }
+        if self.address_size() == OperandSize::Word {
+            return self.rm16(modrm, size);
+        }
+
+        let mut index = None;
+
+        let base = if modrm.rm == 0b100 {
+            let sib = self.next_u8()?;
+            let scale = 1 << (sib >> 6);
+            let idx = ((sib >> 3) & 0b111) | ((self.rex.x as u8) << 3);
+            if idx != 0b100 {
+                index = Some((idx, scale));
+            }
+            let b = sib & 0b111;
+            if b == 0b101 && modrm.mode == 0b00 {
+                let displacement = self.imm32()? as i64;
+                return Ok(self.memory(Addressing::new(None, index, displacement), size));
+            }
+            b | ((self.rex.b as u8) << 3)
+        } else if modrm.rm == 0b101 && modrm.mode == 0b00 {
+            let displacement = self.imm32()? as i64;
+            // RIP-relative addressing only exists in 64-bit code
+            let addressing = match self.code_size {
+                CodeSize::Bits64 => Addressing::RipRelative(displacement),
+                _ => Addressing::Displacement(displacement as u32 as i64),
+            };
+            return Ok(self.memory(addressing, size));
+        } else {
+            modrm.rm | ((self.rex.b as u8) << 3)
+        };
+
+        let displacement = match modrm.mode {
+            0b01 => self.imm8()? as i64,
+            0b10 => self.imm32()? as i64,
+            _ => 0,
+        };
+
+        Ok(self.memory(Addressing::new(Some(base), index, displacement), size))
+    }
+
+    /// Decodes the memory operand of the ModRM byte with 16-bit addressing
+    ///
+    /// The base and index registers are fixed by the r/m field, there is no SIB byte.
+    fn rm16(&mut self, modrm: ModRM, size: OperandSize) -> Result<Dest, DecodeError> {
+        const BX: u8 = 3;
+        const BP: u8 = 5;
+        const SI: u8 = 6;
+        const DI: u8 = 7;
+
+        if modrm.mode == 0b00 && modrm.rm == 0b110 {
+            let displacement = self.next_u16()? as i64;
+            return Ok(self.memory(Addressing::Displacement(displacement), size));
+        }
+        let (base, index) = match modrm.rm {
+            0b000 => (BX, Some(SI)),
+            0b001 => (BX, Some(DI)),
+            0b010 => (BP, Some(SI)),
+            0b011 => (BP, Some(DI)),
+            0b100 => (SI, None),
+            0b101 => (DI, None),
+            0b110 => (BP, None),
+            _ => (BX, None),
+        };
+        let displacement = match modrm.mode {
+            0b01 => self.imm8()? as i64,
+            0b10 => self.next_u16()? as i16 as i64,
+            _ => 0,
+        };
+        let index = index.map(|index| (index, 1));
+        Ok(self.memory(Addressing::new(Some(base), index, displacement), size))
+    }
+
+    /// Builds a memory operand applying the segment override prefix
+    fn memory(&self, addressing: Addressing, size: OperandSize) -> Dest {
+        Dest::Mem(
+            Memory::new(addressing, size)
+                .with_segment(self.prefixes.segment)
+                .with_address_size(self.address_size()),
+        )
+    }
+
+    fn unsupported(&self, opcode: u32) -> DecodeError {
+        DecodeError::UnsupportedOpcode {
+            address: self.start,
+            opcode,
+        }
+    }
+
+    fn decode(&mut self) -> Result<Instr, DecodeError> {
+        let opcode = loop {
+            let byte = self.next_u8()?;
+            match byte {
+                0x66 => self.prefixes.operand_size = true,
+                0x26 => self.prefixes.segment = Some(Segment::Es),
+                0x2e => self.prefixes.segment = Some(Segment::Cs),
+                0x36 => self.prefixes.segment = Some(Segment::Ss),
+                0x3e => self.prefixes.segment = Some(Segment::Ds),
+                0x64 => self.prefixes.segment = Some(Segment::Fs),
+                0x65 => self.prefixes.segment = Some(Segment::Gs),
+                0x67 => self.prefixes.address_size = true,
+                // LOCK and REP have no effect yet
+                0xf0 | 0xf2 | 0xf3 => {}
+                0x40..=0x4f if self.code_size == CodeSize::Bits64 => {
+                    self.rex = Rex::from_byte(byte);
+                    continue;
+                }
+                _ => break byte,
+            }
+            // a REX prefix is only effective when it immediately precedes the opcode
+            self.rex = Rex::default();
+        };
+
+        match opcode {
+            0x0f => self.decode_0f(),
+
+            // ALU operations in the classic encoding
+            0x00..=0x3f if opcode & 0b111 < 6 => {
+                let op = opcode >> 3;
+                let size = self.operand_size_w(opcode);
+                let (dest, src) = match opcode & 0b111 {
+                    0 | 1 => {
+                        let modrm = self.modrm()?;
+                        let reg = self.reg(modrm, size);
+                        (self.rm(modrm, size)?, Src::Reg(reg))
+                    }
+                    2 | 3 => {
+                        let modrm = self.modrm()?;
+                        let reg = self.reg(modrm, size);
+                        (Dest::Reg(reg), self.rm(modrm, size)?.into())
+                    }
+                    _ => (Dest::Reg(Reg::Gpr(0, size)), Src::Imm(self.imm(size)?)),
+                };
+                self.alu(op, dest, src, opcode as u32)
+            }
+            0x80 | 0x81 | 0x83 => {
+                let modrm = self.modrm()?;
+                let size = self.operand_size_w(opcode);
+                let imm_size = if opcode == 0x83 {
+                    OperandSize::Byte
+                } else {
+                    size
+                };
+                let dest = self.rm(modrm, size)?;
+                let src = self.imm(imm_size)?;
+                self.alu(modrm.reg, dest, Src::Imm(src), opcode as u32)
+            }
+
+            // INC and DEC in place of the REX prefixes outside of 64-bit code
+            0x40..=0x47 => Ok(Instr::Inc(Dest::Reg(
+                self.opcode_reg(opcode, self.operand_size()),
+            ))),
+            0x48..=0x4f => Ok(Instr::Dec(Dest::Reg(
+                self.opcode_reg(opcode, self.operand_size()),
+            ))),
+
+            0x50..=0x57 => Ok(Instr::Push(Src::Reg(
+                self.opcode_reg(opcode, self.stack_operand_size()),
+            ))),
+            0x58..=0x5f => Ok(Instr::Pop(Dest::Reg(
+                self.opcode_reg(opcode, self.stack_operand_size()),
+            ))),
+            // PUSH imm16 is not supported in 64-bit code
+            0x68 | 0x6a
+                if self.code_size == CodeSize::Bits64
+                    && self.stack_operand_size() == OperandSize::Word =>
+            {
+                Err(self.unsupported(opcode as u32))
+            }
+            0x68 => Ok(Instr::Push(Src::Imm(self.imm(self.stack_operand_size())?))),
+            0x6a => Ok(Instr::Push(Src::Imm(self.imm8()?))),
+
+            0x70..=0x7f => Ok(Instr::Jcc(Condition::from_code(opcode), self.rel8()?)),
+
+            0x84 | 0x85 => {
+                let modrm = self.modrm()?;
+                let size = self.operand_size_w(opcode);
+                let reg = self.reg(modrm, size);
+                Ok(Instr::Test(self.rm(modrm, size)?, Src::Reg(reg)))
+            }
+            0x88 | 0x89 => {
+                let modrm = self.modrm()?;
+                let size = self.operand_size_w(opcode);
+                let reg = self.reg(modrm, size);
+                Ok(Instr::Mov(self.rm(modrm, size)?, Src::Reg(reg)))
+            }
+            0x8a | 0x8b => {
+                let modrm = self.modrm()?;
+                let size = self.operand_size_w(opcode);
+                let reg = self.reg(modrm, size);
+                Ok(Instr::Mov(Dest::Reg(reg), self.rm(modrm, size)?.into()))
+            }
+            0x8c | 0x8e => {
+                let modrm = self.modrm()?;
+                let segment = match modrm.reg {
+                    0 => Segment::Es,
+                    1 if opcode == 0x8c => Segment::Cs,
+                    2 => Segment::Ss,
+                    3 => Segment::Ds,
+                    4 => Segment::Fs,
+                    5 => Segment::Gs,
+                    // CS cannot be loaded by MOV
+                    _ => return Err(self.unsupported(opcode as u32)),
+                };
+                let operand = self.rm(modrm, OperandSize::Word)?;
+                let segment = Reg::Segment(segment);
+                match opcode {
+                    0x8c => Ok(Instr::Mov(operand, Src::Reg(segment))),
+                    _ => Ok(Instr::Mov(Dest::Reg(segment), operand.into())),
+                }
+            }
+            0x8f => {
+                let modrm = self.modrm()?;
+                match modrm.reg {
+                    0 => Ok(Instr::Pop(self.rm(modrm, self.stack_operand_size())?)),
+                    _ => Err(self.unsupported(opcode as u32)),
+                }
+            }
+
+            0x90 if !self.rex.b => Ok(Instr::Nop),
+
+            0xa8 | 0xa9 => {
+                let size = self.operand_size_w(opcode);
+                Ok(Instr::Test(
+                    Dest::Reg(Reg::Gpr(0, size)),
+                    Src::Imm(self.imm(size)?),
+                ))
+            }
+
+            0xb0..=0xb7 => Ok(Instr::Mov(
+                Dest::Reg(self.opcode_reg(opcode, OperandSize::Byte)),
+                Src::Imm(self.imm8()?),
+            )),
+            0xb8..=0xbf => {
+                let size = self.operand_size();
+                let reg = self.opcode_reg(opcode, size);
+                let imm = match size {
+                    OperandSize::Qword => self.next_u64()?,
+                    size => self.imm(size)?,
+                };
+                Ok(Instr::Mov(Dest::Reg(reg), Src::Imm(imm)))
+            }
+
+            0xc3 => Ok(Instr::Ret),
+            0xc6 | 0xc7 => {
+                let modrm = self.modrm()?;
+                if modrm.reg != 0 {
+                    return Err(self.unsupported(opcode as u32));
+                }
+                let size = self.operand_size_w(opcode);
+                let dest = self.rm(modrm, size)?;
+                Ok(Instr::Mov(dest, Src::Imm(self.imm(size)?)))
+            }
+
+            0xe8 => Ok(Instr::Call(self.rel()?)),
+            0xe9 => Ok(Instr::Jmp(self.rel()?)),
+            0xeb => Ok(Instr::Jmp(self.rel8()?)),
+
+            0xf4 => Ok(Instr::Hlt),
+            0xf6 | 0xf7 => {
+                let modrm = self.modrm()?;
+                let size = self.operand_size_w(opcode);
+                let operand = self.rm(modrm, size)?;
+                match modrm.reg {
+                    0 | 1 => Ok(Instr::Test(operand, Src::Imm(self.imm(size)?))),
+                    2 => Ok(Instr::Not(operand)),
+                    3 => Ok(Instr::Neg(operand)),
+                    7 => Ok(Instr::IDiv(operand.into())),
+                    _ => Err(self.unsupported(opcode as u32)),
+                }
+            }
+            0xfe | 0xff => {
+                let modrm = self.modrm()?;
+                let size = match modrm.reg {
+                    // near branches and PUSH use 64-bit operands in 64-bit code
+                    2 | 4 => self.near_branch_size(),
+                    6 => self.stack_operand_size(),
+                    _ => self.operand_size_w(opcode),
+                };
+                let operand = self.rm(modrm, size)?;
+                match (modrm.reg, opcode) {
+                    (0, _) => Ok(Instr::Inc(operand)),
+                    (1, _) => Ok(Instr::Dec(operand)),
+                    (2, 0xff) => Ok(Instr::Call(operand.into())),
+                    (4, 0xff) => Ok(Instr::Jmp(operand.into())),
+                    (6, 0xff) => Ok(Instr::Push(operand.into())),
+                    _ => Err(self.unsupported(opcode as u32)),
+                }
+            }
+
+            _ => Err(self.unsupported(opcode as u32)),
+        }
+    }
+
+    fn decode_0f(&mut self) -> Result<Instr, DecodeError> {
+        let opcode = self.next_u8()?;
+        match opcode {
+            0x40..=0x4f => {
+                let modrm = self.modrm()?;
+                let size = self.operand_size();
+                let reg = self.reg(modrm, size);
+                Ok(Instr::Cmovcc(
+                    Condition::from_code(opcode),
+                    Dest::Reg(reg),
+                    self.rm(modrm, size)?.into(),
+                ))
+            }
+            0x80..=0x8f => Ok(Instr::Jcc(Condition::from_code(opcode), self.rel()?)),
+            0x90..=0x9f => {
+                let modrm = self.modrm()?;
+                Ok(Instr::Setcc(
+                    Condition::from_code(opcode),
+                    self.rm(modrm, OperandSize::Byte)?,
+                ))
+            }
+            0xaf => {
+                let modrm = self.modrm()?;
+                let size = self.operand_size();
+                let reg = self.reg(modrm, size);
+                Ok(Instr::IMul(Dest::Reg(reg), self.rm(modrm, size)?.into()))
+            }
+            _ => Err(self.unsupported(0x0f00 | opcode as u32)),
+        }
+    }
+
+    /// Maps the 3-bit ALU operation selector to an instruction
+    fn alu(&self, op: u8, dest: Dest, src: Src, opcode: u32) -> Result<Instr, DecodeError> {
+        match op {
+            0 => Ok(Instr::Add(dest, src)),
+            1 => Ok(Instr::Or(dest, src)),
+            4 => Ok(Instr::And(dest, src)),
+            5 => Ok(Instr::Sub(dest, src)),
+            6 => Ok(Instr::Xor(dest, src)),
+            7 => Ok(Instr::Cmp(dest, src)),
+            // ADC and SBB
+            _ => Err(self.unsupported(opcode)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::device::DRAM;
+    use OperandSize::*;
+
+    fn decode_bytes(bytes: &[u8]) -> (Instr, usize) {
+        decode_code(bytes, CodeSize::Bits64)
+    }
+
+    fn decode_code(bytes: &[u8], code_size: CodeSize) -> (Instr, usize) {
+        let mut dram = DRAM::new(0, 1 << 16);
+        dram.alloc(0x1000, bytes.len()).unwrap();
+        dram.write_bytes(0x1000, bytes).unwrap();
+        decode_sized(&dram, 0x1000, code_size).unwrap()
+    }
+
+    fn reg(index: u8, size: OperandSize) -> Reg {
+        Reg::Gpr(index, size)
+    }
+
+    fn mem(addressing: Addressing, size: OperandSize) -> Memory {
+        Memory::new(addressing, size)
+    }
+
+    #[test]
+    fn test_decode_modrm_sib() {
+        // mov rax, rbx
+        assert_eq!(
+            decode_bytes(&[0x48, 0x89, 0xd8]),
+            (
+                Instr::Mov(Dest::Reg(reg(0, Qword)), Src::Reg(reg(3, Qword))),
+                3
+            )
+        );
+        // add r9, [rsi + r10 * 4 + 0x10]
+        assert_eq!(
+            decode_bytes(&[0x4e, 0x03, 0x4c, 0x96, 0x10]),
+            (
+                Instr::Add(
+                    Dest::Reg(reg(9, Qword)),
+                    Src::Mem(mem(
+                        Addressing::BaseIndexScaleDisplacement(6, 10, 4, 0x10),
+                        Qword
+                    ))
+                ),
+                5
+            )
+        );
+        // cmp dword [rbp - 8], 0x7f
+        assert_eq!(
+            decode_bytes(&[0x83, 0x7d, 0xf8, 0x7f]),
+            (
+                Instr::Cmp(
+                    Dest::Mem(mem(Addressing::BaseDisplacement(5, -8), Dword)),
+                    Src::Imm(0x7f)
+                ),
+                4
+            )
+        );
+        // mov dword [rip + 0x10], 1
+        assert_eq!(
+            decode_bytes(&[0xc7, 0x05, 0x10, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]),
+            (
+                Instr::Mov(
+                    Dest::Mem(mem(Addressing::RipRelative(0x10), Dword)),
+                    Src::Imm(1)
+                ),
+                10
+            )
+        );
+    }
+
+    #[test]
+    fn test_decode_operand_sizes() {
+        // mov al, bl
+        assert_eq!(
+            decode_bytes(&[0x88, 0xd8]),
+            (
+                Instr::Mov(Dest::Reg(reg(0, Byte)), Src::Reg(reg(3, Byte))),
+                2
+            )
+        );
+        // mov ah, bh
+        assert_eq!(
+            decode_bytes(&[0x88, 0xfc]),
+            (
+                Instr::Mov(Dest::Reg(Reg::HighByte(0)), Src::Reg(Reg::HighByte(3))),
+                2
+            )
+        );
+        // mov spl, dil
+        assert_eq!(
+            decode_bytes(&[0x40, 0x88, 0xfc]),
+            (
+                Instr::Mov(Dest::Reg(reg(4, Byte)), Src::Reg(reg(7, Byte))),
+                3
+            )
+        );
+        // add word [rbx], 0x1234
+        assert_eq!(
+            decode_bytes(&[0x66, 0x81, 0x03, 0x34, 0x12]),
+            (
+                Instr::Add(Dest::Mem(mem(Addressing::Base(3), Word)), Src::Imm(0x1234)),
+                5
+            )
+        );
+        // push qword [rsp + 8]
+        assert_eq!(
+            decode_bytes(&[0xff, 0x74, 0x24, 0x08]),
+            (
+                Instr::Push(Src::Mem(mem(Addressing::BaseDisplacement(4, 8), Qword))),
+                4
+            )
+        );
+        // sub eax, -1
+        assert_eq!(
+            decode_bytes(&[0x2d, 0xff, 0xff, 0xff, 0xff]),
+            (Instr::Sub(Dest::Reg(reg(0, Dword)), Src::Imm(u64::MAX)), 5)
+        );
+    }
+
+    #[test]
+    fn test_decode_branches() {
+        // jne -2
+        assert_eq!(
+            decode_bytes(&[0x75, 0xfe]),
+            (Instr::Jcc(Condition::Ne, Src::Imm(0x1000)), 2)
+        );
+        // ja +0x10
+        assert_eq!(
+            decode_bytes(&[0x0f, 0x87, 0x10, 0x00, 0x00, 0x00]),
+            (Instr::Jcc(Condition::A, Src::Imm(0x1016)), 6)
+        );
+        // sete [rdi]
+        assert_eq!(
+            decode_bytes(&[0x0f, 0x94, 0x07]),
+            (
+                Instr::Setcc(Condition::E, Dest::Mem(mem(Addressing::Base(7), Byte))),
+                3
+            )
+        );
+        // cmovne rax, rcx
+        assert_eq!(
+            decode_bytes(&[0x48, 0x0f, 0x45, 0xc1]),
+            (
+                Instr::Cmovcc(
+                    Condition::Ne,
+                    Dest::Reg(reg(0, Qword)),
+                    Src::Reg(reg(1, Qword))
+                ),
+                4
+            )
+        );
+        // call +0x100
+        assert_eq!(
+            decode_bytes(&[0xe8, 0x00, 0x01, 0x00, 0x00]),
+            (Instr::Call(Src::Imm(0x1105)), 5)
+        );
+        // jmp r11
+        assert_eq!(
+            decode_bytes(&[0x41, 0xff, 0xe3]),
+            (Instr::Jmp(Src::Reg(reg(11, Qword))), 3)
+        );
+        assert_eq!(decode_bytes(&[0xc3]), (Instr::Ret, 1));
+    }
+
+    #[test]
+    fn test_decode_prefixes() {
+        // a REX prefix followed by a legacy prefix is ignored
+        assert_eq!(
+            decode_bytes(&[0x48, 0x66, 0xb8, 0x34, 0x12]),
+            (Instr::Mov(Dest::Reg(reg(0, Word)), Src::Imm(0x1234)), 5)
+        );
+        // movabs r8, imm64
+        assert_eq!(
+            decode_bytes(&[0x49, 0xb8, 0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01]),
+            (
+                Instr::Mov(Dest::Reg(reg(8, Qword)), Src::Imm(0x0123456789abcdef)),
+                10
+            )
+        );
+        // mov rax, fs:[0x28]
+        assert_eq!(
+            decode_bytes(&[0x64, 0x48, 0x8b, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]),
+            (
+                Instr::Mov(
+                    Dest::Reg(reg(0, Qword)),
+                    Src::Mem(
+                        mem(Addressing::Displacement(0x28), Qword).with_segment(Some(Segment::Fs))
+                    )
+                ),
+                9
+            )
+        );
+        let mut dram = DRAM::new(0, 1 << 16);
+        dram.alloc(0, 16).unwrap();
+        dram.write_bytes(0, &[0x66; 16]).unwrap();
+        assert!(matches!(decode(&dram, 0), Err(DecodeError::TooLong { .. })));
+    }
+
+    #[test]
+    fn test_decode_legacy_code() {
+        use CodeSize::*;
+
+        let mem16 = |addressing, size| Memory::new(addressing, size).with_address_size(Word);
+        // mov ax, [bx + si + 4]
+        assert_eq!(
+            decode_code(&[0x8b, 0x40, 0x04], Bits16),
+            (
+                Instr::Mov(
+                    Dest::Reg(reg(0, Word)),
+                    Src::Mem(mem16(Addressing::BaseIndexDisplacement(3, 6, 4), Word))
+                ),
+                3
+            )
+        );
+        // mov word [bp - 2], 0x1234
+        assert_eq!(
+            decode_code(&[0xc7, 0x46, 0xfe, 0x34, 0x12], Bits16),
+            (
+                Instr::Mov(
+                    Dest::Mem(mem16(Addressing::BaseDisplacement(5, -2), Word)),
+                    Src::Imm(0x1234)
+                ),
+                5
+            )
+        );
+        // mov dl, [0x7c10]
+        assert_eq!(
+            decode_code(&[0x8a, 0x16, 0x10, 0x7c], Bits16),
+            (
+                Instr::Mov(
+                    Dest::Reg(reg(2, Byte)),
+                    Src::Mem(mem16(Addressing::Displacement(0x7c10), Byte))
+                ),
+                4
+            )
+        );
+        // mov eax, 0x12345678
+        assert_eq!(
+            decode_code(&[0x66, 0xb8, 0x78, 0x56, 0x34, 0x12], Bits16),
+            (
+                Instr::Mov(Dest::Reg(reg(0, Dword)), Src::Imm(0x12345678)),
+                6
+            )
+        );
+        // inc cx
+        assert_eq!(
+            decode_code(&[0x41], Bits16),
+            (Instr::Inc(Dest::Reg(reg(1, Word))), 1)
+        );
+        // mov ds, ax
+        assert_eq!(
+            decode_code(&[0x8e, 0xd8], Bits16),
+            (
+                Instr::Mov(Dest::Reg(Reg::Segment(Segment::Ds)), Src::Reg(reg(0, Word))),
+                2
+            )
+        );
+        // push 0x1234
+        assert_eq!(
+            decode_code(&[0x68, 0x34, 0x12], Bits16),
+            (Instr::Push(Src::Imm(0x1234)), 3)
+        );
+        // jmp $ - 0x2000 wraps around at 64 KiB
+        assert_eq!(
+            decode_code(&[0xe9, 0xfd, 0xdf], Bits16),
+            (Instr::Jmp(Src::Imm(0xf000)), 3)
+        );
+
+        // dec eax
+        assert_eq!(
+            decode_code(&[0x48], Bits32),
+            (Instr::Dec(Dest::Reg(reg(0, Dword))), 1)
+        );
+        // mov eax, [0x80001000] is absolute without RIP-relative addressing
+        assert_eq!(
+            decode_code(&[0x8b, 0x05, 0x00, 0x10, 0x00, 0x80], Bits32),
+            (
+                Instr::Mov(
+                    Dest::Reg(reg(0, Dword)),
+                    Src::Mem(
+                        mem(Addressing::Displacement(0x8000_1000), Dword).with_address_size(Dword)
+                    )
+                ),
+                6
+            )
+        );
+        // mov cs, ax cannot be encoded
+        let mut dram = DRAM::new(0, 0x10);
+        dram.alloc(0, 2).unwrap();
+        dram.write_bytes(0, &[0x8e, 0xc8]).unwrap();
+        assert!(decode_sized(&dram, 0, Bits16).is_err());
+    }
+}
@@ -0,0 +1,584 @@
+//! Formatting of decoded instructions in Intel and AT&T syntax
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Write};
+
+use crate::instruction::{
+    Addressing, Condition, Dest, Instr, Memory, OperandSize, Reg, Segment, Src,
+};
+
+/// The assembly syntax produced by the disassembler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Syntax {
+    /// `mov qword ptr [rbp - 0x8], rax`
+    #[default]
+    Intel,
+    /// `mov %rax,-0x8(%rbp)`
+    Att,
+}
+
+/// Maps addresses to symbol names
+pub trait SymbolResolver {
+    /// Returns the symbol containing `address` and the offset of `address` into it
+    fn resolve(&self, address: u64) -> Option<(&str, u64)>;
+}
+
+/// Symbols covering address ranges
+///
+/// Addresses outside of every range are not resolved, so addresses past the
+/// last symbol are printed as plain numbers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    /// The name and end address of every symbol, indexed by start address
+    symbols: BTreeMap<u64, (String, u64)>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the symbol `name` covering `start..end`
+    pub fn insert(&mut self, name: impl Into<String>, start: u64, end: u64) {
+        self.symbols.insert(start, (name.into(), end));
+    }
+}
+
+/// Resolves an address to the closest symbol at or below it that still covers it
+impl SymbolResolver for SymbolTable {
+    fn resolve(&self, address: u64) -> Option<(&str, u64)> {
+        self.symbols
+            .range(..=address)
+            .next_back()
+            .filter(|(_, (_, end))| address < *end)
+            .map(|(start, (name, _))| (name.as_str(), address - start))
+    }
+}
+
+/// Formats instructions in a given syntax
+///
+/// Branch targets and RIP-relative operands are printed with the name of
+/// their symbol if a resolver is attached.
+#[derive(Clone, Copy, Default)]
+pub struct Disassembler<'a> {
+    syntax: Syntax,
+    symbols: Option<&'a dyn SymbolResolver>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(syntax: Syntax) -> Self {
+        Self {
+            syntax,
+            symbols: None,
+        }
+    }
+
+    /// Returns the disassembler resolving addresses with `symbols`
+    pub fn with_symbols(self, symbols: &'a dyn SymbolResolver) -> Self {
+        Self {
+            symbols: Some(symbols),
+            ..self
+        }
+    }
+
+    /// Formats `instr`
+    ///
+    /// `next_rip` is the address of the following instruction, it is required
+    /// to resolve RIP-relative operands.
+    pub fn format(&self, instr: &Instr, next_rip: Option<u64>) -> String {
+        let mut output = String::new();
+        self.write(&mut output, instr, next_rip)
+            .expect("writing to a String never fails");
+        output
+    }
+
+    /// Writes `instr` to `f`, see [`Disassembler::format`]
+    pub fn write(&self, f: &mut dyn Write, instr: &Instr, next_rip: Option<u64>) -> fmt::Result {
+        let printer = Printer {
+            syntax: self.syntax,
+            symbols: self.symbols,
+            next_rip,
+            size: instr.operand_size(),
+            comment: Cell::new(None),
+        };
+        printer.instr(f, instr)?;
+        match printer.comment.get() {
+            Some(target) => write!(f, "  # {target:#x}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Formats instructions in Intel syntax without symbols
+impl Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Disassembler::default().write(f, self, None)
+    }
+}
+
+struct Printer<'a> {
+    syntax: Syntax,
+    symbols: Option<&'a dyn SymbolResolver>,
+    next_rip: Option<u64>,
+    /// The operand size of the instruction, used for immediates
+    size: OperandSize,
+    /// The target of a RIP-relative operand without a symbol, printed as a comment
+    comment: Cell<Option<u64>>,
+}
+
+impl Printer<'_> {
+    fn instr(&self, f: &mut dyn Write, instr: &Instr) -> fmt::Result {
+        match *instr {
+            Instr::Mov(dest, src) => self.binary(f, "mov", dest, src),
+            Instr::Push(src) => self.unary(f, "push", src),
+            Instr::Pop(dest) => self.unary(f, "pop", dest.into()),
+
+            Instr::Add(dest, src) => self.binary(f, "add", dest, src),
+            Instr::Sub(dest, src) => self.binary(f, "sub", dest, src),
+            Instr::Inc(dest) => self.unary(f, "inc", dest.into()),
+            Instr::Dec(dest) => self.unary(f, "dec", dest.into()),
+            Instr::IMul(dest, src) => self.binary(f, "imul", dest, src),
+            Instr::IDiv(src) => self.unary(f, "idiv", src),
+
+            Instr::And(dest, src) => self.binary(f, "and", dest, src),
+            Instr::Or(dest, src) => self.binary(f, "or", dest, src),
+            Instr::Xor(dest, src) => self.binary(f, "xor", dest, src),
+            Instr::Not(dest) => self.unary(f, "not", dest.into()),
+            Instr::Neg(dest) => self.unary(f, "neg", dest.into()),
+
+            Instr::Cmp(dest, src) => self.binary(f, "cmp", dest, src),
+            Instr::Test(dest, src) => self.binary(f, "test", dest, src),
+            Instr::Setcc(condition, dest) => {
+                write!(f, "set{} ", condition_name(condition))?;
+                self.operand(f, dest.into())
+            }
+            Instr::Cmovcc(condition, dest, src) => {
+                write!(f, "cmov{} ", condition_name(condition))?;
+                self.operands(f, dest, src)
+            }
+
+            Instr::Jmp(target) => self.branch(f, "jmp", target),
+            Instr::Jcc(condition, target) => {
+                self.branch(f, &format!("j{}", condition_name(condition)), target)
+            }
+            Instr::Call(target) => self.branch(f, "call", target),
+            Instr::Ret => f.write_str("ret"),
+
+            Instr::Nop => f.write_str("nop"),
+            Instr::Hlt => f.write_str("hlt"),
+        }
+    }
+
+    /// Writes the mnemonic, with a size suffix in AT&T syntax if no register
+    /// operand implies the operand size
+    fn mnemonic(&self, f: &mut dyn Write, mnemonic: &str, operands: &[Src]) -> fmt::Result {
+        f.write_str(mnemonic)?;
+        if self.syntax == Syntax::Att && !operands.iter().any(|op| matches!(op, Src::Reg(_))) {
+            f.write_char(match self.size {
+                OperandSize::Byte => 'b',
+                OperandSize::Word => 'w',
+                OperandSize::Dword => 'l',
+                OperandSize::Qword => 'q',
+            })?;
+        }
+        Ok(())
+    }
+
+    fn unary(&self, f: &mut dyn Write, mnemonic: &str, operand: Src) -> fmt::Result {
+        self.mnemonic(f, mnemonic, &[operand])?;
+        f.write_char(' ')?;
+        self.operand(f, operand)
+    }
+
+    fn binary(&self, f: &mut dyn Write, mnemonic: &str, dest: Dest, src: Src) -> fmt::Result {
+        self.mnemonic(f, mnemonic, &[dest.into(), src])?;
+        f.write_char(' ')?;
+        self.operands(f, dest, src)
+    }
+
+    /// Writes two operands in the order of the syntax
+    fn operands(&self, f: &mut dyn Write, dest: Dest, src: Src) -> fmt::Result {
+        let (first, second) = match self.syntax {
+            Syntax::Intel => (dest.into(), src),
+            Syntax::Att => (src, dest.into()),
+        };
+        self.operand(f, first)?;
+        f.write_str(match self.syntax {
+            Syntax::Intel => ", ",
+            Syntax::Att => ",",
+        })?;
+        self.operand(f, second)
+    }
+
+    /// Writes a branch, immediate targets are absolute addresses
+    fn branch(&self, f: &mut dyn Write, mnemonic: &str, target: Src) -> fmt::Result {
+        match target {
+            Src::Imm(address) => {
+                write!(f, "{mnemonic} {address:#x}")?;
+                if let Some((name, offset)) = self.resolve(address) {
+                    f.write_str(" <")?;
+                    write_symbol(f, name, offset)?;
+                    f.write_char('>')?;
+                }
+                Ok(())
+            }
+            target => {
+                self.mnemonic(f, mnemonic, &[target])?;
+                f.write_char(' ')?;
+                if self.syntax == Syntax::Att {
+                    f.write_char('*')?;
+                }
+                self.operand(f, target)
+            }
+        }
+    }
+
+    fn operand(&self, f: &mut dyn Write, operand: Src) -> fmt::Result {
+        match operand {
+            Src::Reg(reg) => {
+                if self.syntax == Syntax::Att {
+                    f.write_char('%')?;
+                }
+                f.write_str(register_name(reg))
+            }
+            Src::Mem(mem) => match self.syntax {
+                Syntax::Intel => self.intel_memory(f, mem),
+                Syntax::Att => self.att_memory(f, mem),
+            },
+            Src::Imm(value) => {
+                if self.syntax == Syntax::Att {
+                    f.write_char('$')?;
+                }
+                write!(f, "{:#x}", value & self.size.mask())
+            }
+        }
+    }
+
+    fn intel_memory(&self, f: &mut dyn Write, mem: Memory) -> fmt::Result {
+        let size = match mem.size {
+            OperandSize::Byte => "byte",
+            OperandSize::Word => "word",
+            OperandSize::Dword => "dword",
+            OperandSize::Qword => "qword",
+        };
+        write!(f, "{size} ptr ")?;
+        if let Some(segment) = mem.segment {
+            write!(f, "{}:", segment_name(segment))?;
+        }
+
+        let (base, index, displacement) = mem.addressing.components();
+        f.write_char('[')?;
+        match mem.addressing {
+            Addressing::RipRelative(_) => {
+                f.write_str(ip_name(mem))?;
+                match self.rip_target(displacement) {
+                    Some((name, offset)) => {
+                        f.write_str(" + ")?;
+                        write_symbol(f, name, offset)?;
+                    }
+                    None => write_displacement(f, displacement)?,
+                }
+            }
+            Addressing::Displacement(_) => self.absolute(f, displacement)?,
+            _ => {
+                if let Some(base) = base {
+                    f.write_str(register_name(Reg::Gpr(base, mem.address_size)))?;
+                }
+                if let Some((index, scale)) = index {
+                    if base.is_some() {
+                        f.write_str(" + ")?;
+                    }
+                    f.write_str(register_name(Reg::Gpr(index, mem.address_size)))?;
+                    if scale != 1 {
+                        write!(f, "*{scale}")?;
+                    }
+                }
+                write_displacement(f, displacement)?;
+            }
+        }
+        self.rip_comment(mem);
+        f.write_char(']')
+    }
+
+    fn att_memory(&self, f: &mut dyn Write, mem: Memory) -> fmt::Result {
+        if let Some(segment) = mem.segment {
+            write!(f, "%{}:", segment_name(segment))?;
+        }
+
+        let (base, index, displacement) = mem.addressing.components();
+        match mem.addressing {
+            Addressing::RipRelative(_) => {
+                match self.rip_target(displacement) {
+                    Some((name, offset)) => write_symbol(f, name, offset)?,
+                    None => write_signed(f, displacement)?,
+                }
+                write!(f, "(%{})", ip_name(mem))?;
+            }
+            Addressing::Displacement(_) => self.absolute(f, displacement)?,
+            _ => {
+                if displacement != 0 {
+                    write_signed(f, displacement)?;
+                }
+                f.write_char('(')?;
+                if let Some(base) = base {
+                    write!(f, "%{}", register_name(Reg::Gpr(base, mem.address_size)))?;
+                }
+                if let Some((index, scale)) = index {
+                    write!(
+                        f,
+                        ",%{},{scale}",
+                        register_name(Reg::Gpr(index, mem.address_size))
+                    )?;
+                }
+                f.write_char(')')?;
+            }
+        }
+        self.rip_comment(mem);
+        Ok(())
+    }
+
+    /// Writes an absolute address, with its symbol if one is known
+    fn absolute(&self, f: &mut dyn Write, address: i64) -> fmt::Result {
+        match self.resolve(address as u64) {
+            Some((name, offset)) => write_symbol(f, name, offset),
+            None => write!(f, "{:#x}", address as u64),
+        }
+    }
+
+    /// Records the target address of a RIP-relative operand without a symbol
+    fn rip_comment(&self, mem: Memory) {
+        if let Addressing::RipRelative(displacement) = mem.addressing {
+            if let (Some(next_rip), None) = (self.next_rip, self.rip_target(displacement)) {
+                self.comment
+                    .set(Some(next_rip.wrapping_add_signed(displacement)));
+            }
+        }
+    }
+
+    fn rip_target(&self, displacement: i64) -> Option<(&str, u64)> {
+        self.resolve(self.next_rip?.wrapping_add_signed(displacement))
+    }
+
+    fn resolve(&self, address: u64) -> Option<(&str, u64)> {
+        self.symbols?.resolve(address)
+    }
+}
+
+/// Returns the name of the instruction pointer used by RIP-relative addressing
+fn ip_name(mem: Memory) -> &'static str {
+    match mem.address_size {
+        OperandSize::Dword => "eip",
+        _ => "rip",
+    }
+}
+
+/// Writes ` + 0x10` or ` - 0x10`, nothing for a zero displacement
+fn write_displacement(f: &mut dyn Write, displacement: i64) -> fmt::Result {
+    match displacement {
+        0 => Ok(()),
+        d if d < 0 => write!(f, " - {:#x}", d.unsigned_abs()),
+        d => write!(f, " + {d:#x}"),
+    }
+}
+
+/// Writes `0x10` or `-0x10`
+fn write_signed(f: &mut dyn Write, value: i64) -> fmt::Result {
+    if value < 0 {
+        write!(f, "-{:#x}", value.unsigned_abs())
+    } else {
+        write!(f, "{value:#x}")
+    }
+}
+
+fn write_symbol(f: &mut dyn Write, name: &str, offset: u64) -> fmt::Result {
+    if offset == 0 {
+        f.write_str(name)
+    } else {
+        write!(f, "{name}+{offset:#x}")
+    }
+}
+
+/// Returns the name of a register without the AT&T `%` prefix
+pub fn register_name(reg: Reg) -> &'static str {
+    const QWORD: [&str; 16] = [
+        "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12",
+        "r13", "r14", "r15",
+    ];
+    const DWORD: [&str; 16] = [
+        "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d",
+        "r12d", "r13d", "r14d", "r15d",
+    ];
+    const WORD: [&str; 16] = [
+        "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w",
+        "r13w", "r14w", "r15w",
+    ];
+    const BYTE: [&str; 16] = [
+        "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+        "r13b", "r14b", "r15b",
+    ];
+    const HIGH_BYTE: [&str; 4] = ["ah", "ch", "dh", "bh"];
+
+    match reg {
+        Reg::Gpr(index, OperandSize::Qword) => QWORD[index as usize & 0xf],
+        Reg::Gpr(index, OperandSize::Dword) => DWORD[index as usize & 0xf],
+        Reg::Gpr(index, OperandSize::Word) => WORD[index as usize & 0xf],
+        Reg::Gpr(index, OperandSize::Byte) => BYTE[index as usize & 0xf],
+        Reg::HighByte(index) => HIGH_BYTE[index as usize & 0x3],
+        Reg::Segment(segment) => segment_name(segment),
+    }
+}
+
+/// Returns the mnemonic suffix of a condition code
+pub fn condition_name(condition: Condition) -> &'static str {
+    const NAMES: [&str; 16] = [
+        "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+    ];
+    NAMES[condition.code() as usize]
+}
+
+fn segment_name(segment: Segment) -> &'static str {
+    match segment {
+        Segment::Es => "es",
+        Segment::Cs => "cs",
+        Segment::Ss => "ss",
+        Segment::Ds => "ds",
+        Segment::Fs => "fs",
+        Segment::Gs => "gs",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use OperandSize::*;
+
+    fn both(instr: Instr, next_rip: Option<u64>, symbols: &SymbolTable) -> (String, String) {
+        (
+            Disassembler::new(Syntax::Intel)
+                .with_symbols(symbols)
+                .format(&instr, next_rip),
+            Disassembler::new(Syntax::Att)
+                .with_symbols(symbols)
+                .format(&instr, next_rip),
+        )
+    }
+
+    #[test]
+    fn test_intel_and_att() {
+        let symbols = SymbolTable::new();
+        let cases = [
+            (
+                Instr::Mov(Dest::Reg(Reg::Gpr(0, Qword)), Src::Reg(Reg::Gpr(3, Qword))),
+                "mov rax, rbx",
+                "mov %rbx,%rax",
+            ),
+            (
+                Instr::Add(
+                    Dest::Reg(Reg::Gpr(9, Qword)),
+                    Src::Mem(Memory::new(
+                        Addressing::BaseIndexScaleDisplacement(6, 10, 4, 0x10),
+                        Qword,
+                    )),
+                ),
+                "add r9, qword ptr [rsi + r10*4 + 0x10]",
+                "add 0x10(%rsi,%r10,4),%r9",
+            ),
+            (
+                Instr::Cmp(
+                    Dest::Mem(Memory::new(Addressing::BaseDisplacement(5, -8), Dword)),
+                    Src::Imm(-1i64 as u64),
+                ),
+                "cmp dword ptr [rbp - 0x8], 0xffffffff",
+                "cmpl $0xffffffff,-0x8(%rbp)",
+            ),
+            (
+                Instr::Mov(
+                    Dest::Reg(Reg::HighByte(0)),
+                    Src::Mem(
+                        Memory::new(Addressing::Displacement(0x28), Byte)
+                            .with_segment(Some(Segment::Fs)),
+                    ),
+                ),
+                "mov ah, byte ptr fs:[0x28]",
+                "mov %fs:0x28,%ah",
+            ),
+            (
+                Instr::Setcc(Condition::Le, Dest::Reg(Reg::Gpr(7, Byte))),
+                "setle dil",
+                "setle %dil",
+            ),
+            (
+                Instr::Call(Src::Mem(Memory::new(Addressing::Base(0), Qword))),
+                "call qword ptr [rax]",
+                "callq *(%rax)",
+            ),
+            (
+                Instr::Jcc(Condition::Ne, Src::Imm(0x1010)),
+                "jne 0x1010",
+                "jne 0x1010",
+            ),
+        ];
+        for (instr, intel, att) in cases {
+            assert_eq!(both(instr, None, &symbols), (intel.into(), att.into()));
+        }
+        assert_eq!(
+            Instr::Push(Src::Reg(Reg::Gpr(13, Qword))).to_string(),
+            "push r13"
+        );
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("main", 0x1000, 0x1800);
+        symbols.insert("data", 0x2000, 0x2010);
+
+        assert_eq!(
+            both(Instr::Jmp(Src::Imm(0x1010)), None, &symbols),
+            (
+                "jmp 0x1010 <main+0x10>".into(),
+                "jmp 0x1010 <main+0x10>".into()
+            )
+        );
+
+        let load = Instr::Mov(
+            Dest::Reg(Reg::Gpr(0, Dword)),
+            Src::Mem(Memory::new(Addressing::RipRelative(0xff9), Dword)),
+        );
+        assert_eq!(
+            both(load, Some(0x1007), &symbols),
+            (
+                "mov eax, dword ptr [rip + data]".into(),
+                "mov data(%rip),%eax".into()
+            )
+        );
+        assert_eq!(
+            both(load, Some(0x10), &SymbolTable::new()),
+            (
+                "mov eax, dword ptr [rip + 0xff9]  # 0x1009".into(),
+                "mov 0xff9(%rip),%eax  # 0x1009".into()
+            )
+        );
+        assert_eq!(load.to_string(), "mov eax, dword ptr [rip + 0xff9]");
+
+        // addresses between or past the symbols are not resolved
+        for target in [0x1900, 0x2010] {
+            assert_eq!(
+                both(Instr::Jmp(Src::Imm(target)), None, &symbols).0,
+                format!("jmp {target:#x}")
+            );
+        }
+        let load = Instr::Mov(
+            Dest::Reg(Reg::Gpr(0, Dword)),
+            Src::Mem(Memory::new(Addressing::RipRelative(0x10), Dword).with_address_size(Dword)),
+        );
+        assert_eq!(
+            both(load, None, &SymbolTable::new()),
+            (
+                "mov eax, dword ptr [eip + 0x10]".into(),
+                "mov 0x10(%eip),%eax".into()
+            )
+        );
+    }
+}
@@ -0,0 +1,790 @@
+use thiserror::Error;
+
+use crate::instruction::{Addressing, Dest, Instr, Memory, OperandSize, Reg, Segment, Src};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The operand combination has no encoding
+    #[error("Invalid operands for `{0}`")]
+    InvalidOperands(Instr),
+
+    /// A displacement or immediate does not fit in its field
+    #[error("The value {value:#x} does not fit in {bits} bits")]
+    OutOfRange { value: u64, bits: u32 },
+
+    /// The relative branch target cannot be reached with a 32-bit displacement
+    #[error("The branch target {target:#x} is out of range of {address:#x}")]
+    BranchOutOfRange { address: u64, target: u64 },
+}
+
+/// Encodes `instr` located at `address` into machine code
+///
+/// The shortest legal encoding is chosen: sign-extended 8-bit immediates,
+/// the accumulator short forms and 8-bit branch displacements are used when
+/// possible. `address` is only needed to compute relative branch targets.
+pub fn encode(instr: &Instr, address: u64) -> Result<Vec<u8>, EncodeError> {
+    let mut encoder = Encoder::new(*instr, address);
+    encoder.encode()?;
+    encoder.emit()
+}
+
+struct Encoder {
+    instr: Instr,
+    address: u64,
+    /// The operand size override prefix
+    operand_size: bool,
+    segment: Option<Segment>,
+    /// The W, R, X and B bits of the REX prefix
+    rex: u8,
+    /// SPL, BPL, SIL or DIL is used and needs an empty REX prefix
+    rex_required: bool,
+    /// AH, CH, DH or BH is used and cannot be combined with a REX prefix
+    rex_forbidden: bool,
+    /// A segment register is used outside of MOV
+    segment_register: bool,
+    opcode: Vec<u8>,
+    modrm: Option<u8>,
+    sib: Option<u8>,
+    displacement: Vec<u8>,
+    immediate: Vec<u8>,
+}
+
+const REX_W: u8 = 0b1000;
+const REX_R: u8 = 0b0100;
+const REX_X: u8 = 0b0010;
+const REX_B: u8 = 0b0001;
+
+impl Encoder {
+    fn new(instr: Instr, address: u64) -> Self {
+        Self {
+            instr,
+            address,
+            operand_size: false,
+            segment: None,
+            rex: 0,
+            rex_required: false,
+            rex_forbidden: false,
+            segment_register: false,
+            opcode: Vec::new(),
+            modrm: None,
+            sib: None,
+            displacement: Vec::new(),
+            immediate: Vec::new(),
+        }
+    }
+
+    fn invalid(&self) -> EncodeError {
+        EncodeError::InvalidOperands(self.instr)
+    }
+
+    fn encode(&mut self) -> Result<(), EncodeError> {
+        let size = self.instr.operand_size();
+        match self.instr {
+            Instr::Mov(Dest::Reg(Reg::Segment(_)), Src::Reg(Reg::Segment(_))) => {
+                Err(self.invalid())
+            }
+            Instr::Mov(dest, Src::Reg(Reg::Segment(segment))) => {
+                self.mov_segment(0x8c, segment, dest.into())
+            }
+            Instr::Mov(Dest::Reg(Reg::Segment(segment)), src) if segment != Segment::Cs => {
+                self.mov_segment(0x8e, segment, src)
+            }
+            Instr::Mov(dest, src) => self.mov(dest, src, size),
+            Instr::Push(src) => self.push(src, size),
+            Instr::Pop(dest) => self.pop(dest, size),
+
+            Instr::Add(dest, src) => self.alu(0, dest, src, size),
+            Instr::Or(dest, src) => self.alu(1, dest, src, size),
+            Instr::And(dest, src) => self.alu(4, dest, src, size),
+            Instr::Sub(dest, src) => self.alu(5, dest, src, size),
+            Instr::Xor(dest, src) => self.alu(6, dest, src, size),
+            Instr::Cmp(dest, src) => self.alu(7, dest, src, size),
+
+            Instr::Inc(dest) => self.group(0xfe, 0, dest, size),
+            Instr::Dec(dest) => self.group(0xfe, 1, dest, size),
+            Instr::Not(dest) => self.group(0xf6, 2, dest, size),
+            Instr::Neg(dest) => self.group(0xf6, 3, dest, size),
+            Instr::IDiv(Src::Reg(reg)) => self.group(0xf6, 7, Dest::Reg(reg), size),
+            Instr::IDiv(Src::Mem(mem)) => self.group(0xf6, 7, Dest::Mem(mem), size),
+            Instr::IDiv(Src::Imm(_)) => Err(self.invalid()),
+
+            Instr::Test(dest, src) => self.test(dest, src, size),
+            Instr::IMul(Dest::Reg(reg), src) if size != OperandSize::Byte => {
+                self.reg_rm(&[0x0f, 0xaf], reg, src, size)
+            }
+            Instr::Cmovcc(condition, Dest::Reg(reg), src) if size != OperandSize::Byte => {
+                self.reg_rm(&[0x0f, 0x40 | condition.code()], reg, src, size)
+            }
+            Instr::Setcc(condition, dest) if size == OperandSize::Byte => {
+                self.opcode = vec![0x0f, 0x90 | condition.code()];
+                self.rm(0, dest)
+            }
+
+            Instr::Jmp(Src::Imm(target)) => self.branch(&[0xeb], &[0xe9], target),
+            Instr::Jcc(condition, Src::Imm(target)) => self.branch(
+                &[0x70 | condition.code()],
+                &[0x0f, 0x80 | condition.code()],
+                target,
+            ),
+            Instr::Call(Src::Imm(target)) => self.branch(&[], &[0xe8], target),
+            Instr::Jmp(target) => self.indirect_branch(4, target),
+            Instr::Call(target) => self.indirect_branch(2, target),
+
+            Instr::Ret => self.plain(0xc3),
+            Instr::Nop => self.plain(0x90),
+            Instr::Hlt => self.plain(0xf4),
+
+            Instr::IMul(..) | Instr::Cmovcc(..) | Instr::Setcc(..) | Instr::Jcc(..) => {
+                Err(self.invalid())
+            }
+        }
+    }
+
+    fn emit(self) -> Result<Vec<u8>, EncodeError> {
+        let rex_present = self.rex != 0 || self.rex_required;
+        if (rex_present && self.rex_forbidden) || self.segment_register {
+            return Err(self.invalid());
+        }
+
+        let mut bytes = Vec::with_capacity(15);
+        if let Some(segment) = self.segment {
+            bytes.push(match segment {
+                Segment::Es => 0x26,
+                Segment::Cs => 0x2e,
+                Segment::Ss => 0x36,
+                Segment::Ds => 0x3e,
+                Segment::Fs => 0x64,
+                Segment::Gs => 0x65,
+            });
+        }
+        if self.operand_size {
+            bytes.push(0x66);
+        }
+        if rex_present {
+            bytes.push(0x40 | self.rex);
+        }
+        bytes.extend_from_slice(&self.opcode);
+        bytes.extend(self.modrm);
+        bytes.extend(self.sib);
+        bytes.extend_from_slice(&self.displacement);
+        bytes.extend_from_slice(&self.immediate);
+        Ok(bytes)
+    }
+
+    /// Encodes an instruction consisting only of its opcode
+    fn plain(&mut self, opcode: u8) -> Result<(), EncodeError> {
+        self.opcode = vec![opcode];
+        Ok(())
+    }
+
+    /// Selects the operand size with the 0x66 prefix or REX.W
+    fn size(&mut self, size: OperandSize) {
+        match size {
+            OperandSize::Word => self.operand_size = true,
+            OperandSize::Qword => self.rex |= REX_W,
+            OperandSize::Byte | OperandSize::Dword => {}
+        }
+    }
+
+    /// Returns the opcode with the low bit set for non-byte operands
+    fn opcode_w(opcode: u8, size: OperandSize) -> u8 {
+        match size {
+            OperandSize::Byte => opcode,
+            _ => opcode | 1,
+        }
+    }
+
+    /// Returns the low three bits of a register encoding, recording the REX bit
+    /// `rex` to use for the fourth bit
+    fn register(&mut self, reg: Reg, rex: u8) -> u8 {
+        match reg {
+            Reg::Gpr(index, size) => {
+                if index & 0b1000 != 0 {
+                    self.rex |= rex;
+                }
+                if size == OperandSize::Byte && (4..8).contains(&index) {
+                    self.rex_required = true;
+                }
+                index & 0b111
+            }
+            Reg::HighByte(index) => {
+                self.rex_forbidden = true;
+                4 + (index & 0b11)
+            }
+            Reg::Segment(segment) => {
+                self.segment_register = true;
+                segment as u8
+            }
+        }
+    }
+
+    /// Encodes the ModRM byte with `reg` in the reg field and `rm` as operand
+    fn rm(&mut self, reg: u8, rm: Dest) -> Result<(), EncodeError> {
+        match rm {
+            Dest::Reg(r) => {
+                let r = self.register(r, REX_B);
+                self.modrm = Some(0b11 << 6 | reg << 3 | r);
+                Ok(())
+            }
+            Dest::Mem(mem) => self.memory(reg, mem),
+        }
+    }
+
+    fn memory(&mut self, reg: u8, mem: Memory) -> Result<(), EncodeError> {
+        // only 64-bit addressing is encoded
+        if mem.address_size != OperandSize::Qword {
+            return Err(self.invalid());
+        }
+        self.segment = mem.segment;
+        let (base, index, displacement) = mem.addressing.components();
+
+        match mem.addressing {
+            Addressing::RipRelative(_) => {
+                self.modrm = Some(reg << 3 | 0b101);
+                self.displacement = disp32(displacement)?.to_vec();
+                return Ok(());
+            }
+            Addressing::Displacement(_) => {
+                // SIB without base and index
+                self.modrm = Some(reg << 3 | 0b100);
+                self.sib = Some(0b100 << 3 | 0b101);
+                self.displacement = disp32(displacement)?.to_vec();
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let index = match index {
+            // RSP cannot be used as index
+            Some((4, _)) => return Err(self.invalid()),
+            Some((index, scale)) => {
+                let scale = match scale {
+                    1 => 0,
+                    2 => 1,
+                    4 => 2,
+                    8 => 3,
+                    _ => return Err(self.invalid()),
+                };
+                if index & 0b1000 != 0 {
+                    self.rex |= REX_X;
+                }
+                Some((index & 0b111, scale))
+            }
+            None => None,
+        };
+
+        let Some(base) = base else {
+            // Index * Scale + disp32
+            let (index, scale) = index.expect("addressing without base has an index");
+            self.modrm = Some(reg << 3 | 0b100);
+            self.sib = Some(scale << 6 | index << 3 | 0b101);
+            self.displacement = disp32(displacement)?.to_vec();
+            return Ok(());
+        };
+        if base & 0b1000 != 0 {
+            self.rex |= REX_B;
+        }
+        let base = base & 0b111;
+
+        // RBP and R13 as base always need a displacement
+        let mode = if displacement == 0 && base != 0b101 {
+            self.displacement.clear();
+            0b00
+        } else if let Ok(displacement) = i8::try_from(displacement) {
+            self.displacement = vec![displacement as u8];
+            0b01
+        } else {
+            self.displacement = disp32(displacement)?.to_vec();
+            0b10
+        };
+
+        match index {
+            Some((index, scale)) => {
+                self.modrm = Some(mode << 6 | reg << 3 | 0b100);
+                self.sib = Some(scale << 6 | index << 3 | base);
+            }
+            // RSP and R12 as base need a SIB byte
+            None if base == 0b100 => {
+                self.modrm = Some(mode << 6 | reg << 3 | 0b100);
+                self.sib = Some(0b100 << 3 | base);
+            }
+            None => self.modrm = Some(mode << 6 | reg << 3 | base),
+        }
+        Ok(())
+    }
+
+    /// Encodes an immediate of the given size, 64-bit operands take a
+    /// sign-extended 32-bit immediate
+    fn immediate(&mut self, value: u64, size: OperandSize) -> Result<(), EncodeError> {
+        self.immediate = match size {
+            OperandSize::Qword => imm32(value)?.to_vec(),
+            size => truncate(value, size)?.to_le_bytes()[..size.bytes()].to_vec(),
+        };
+        Ok(())
+    }
+
+    /// Checks that the source operand has the size of the instruction
+    fn check_size(&self, src: Src, size: OperandSize) -> Result<(), EncodeError> {
+        match src.size() {
+            Some(src_size) if src_size != size => Err(self.invalid()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Encodes `opcode reg, r/m`
+    fn reg_rm(
+        &mut self,
+        opcode: &[u8],
+        reg: Reg,
+        src: Src,
+        size: OperandSize,
+    ) -> Result<(), EncodeError> {
+        self.check_size(src, size)?;
+        let rm = match src {
+            Src::Reg(r) => Dest::Reg(r),
+            Src::Mem(mem) => Dest::Mem(mem),
+            Src::Imm(_) => return Err(self.invalid()),
+        };
+        self.size(size);
+        self.opcode = opcode.to_vec();
+        let reg = self.register(reg, REX_R);
+        self.rm(reg, rm)
+    }
+
+    /// Encodes `opcode r/m, reg`
+    fn rm_reg(
+        &mut self,
+        opcode: u8,
+        dest: Dest,
+        reg: Reg,
+        size: OperandSize,
+    ) -> Result<(), EncodeError> {
+        self.size(size);
+        self.opcode = vec![Self::opcode_w(opcode, size)];
+        let reg = self.register(reg, REX_R);
+        self.rm(reg, dest)
+    }
+
+    /// Encodes a single operand instruction of the F6, F7, FE and FF groups
+    fn group(
+        &mut self,
+        opcode: u8,
+        op: u8,
+        dest: Dest,
+        size: OperandSize,
+    ) -> Result<(), EncodeError> {
+        self.size(size);
+        self.opcode = vec![Self::opcode_w(opcode, size)];
+        self.rm(op, dest)
+    }
+
+    fn mov(&mut self, dest: Dest, src: Src, size: OperandSize) -> Result<(), EncodeError> {
+        self.check_size(src, size)?;
+        match (dest, src) {
+            (dest, Src::Reg(reg)) => self.rm_reg(0x88, dest, reg, size),
+            (Dest::Reg(reg), src) if !matches!(src, Src::Imm(_)) => {
+                self.reg_rm(&[Self::opcode_w(0x8a, size)], reg, src, size)
+            }
+            // MOV r64, imm32 is sign-extended by C7 which is shorter than MOV r64, imm64
+            (Dest::Reg(reg), Src::Imm(value))
+                if size != OperandSize::Qword || imm32(value).is_err() =>
+            {
+                self.size(size);
+                let r = self.register(reg, REX_B);
+                self.opcode = vec![
+                    if size == OperandSize::Byte {
+                        0xb0
+                    } else {
+                        0xb8
+                    } | r,
+                ];
+                match size {
+                    OperandSize::Qword => self.immediate = value.to_le_bytes().to_vec(),
+                    size => self.immediate(value, size)?,
+                }
+                Ok(())
+            }
+            (dest, Src::Imm(value)) => {
+                self.group(0xc6, 0, dest, size)?;
+                self.immediate(value, size)
+            }
+            _ => Err(self.invalid()),
+        }
+    }
+
+    /// Encodes MOV between a segment register and a 16-bit r/m operand
+    fn mov_segment(&mut self, opcode: u8, segment: Segment, rm: Src) -> Result<(), EncodeError> {
+        let rm = match rm {
+            Src::Reg(reg) if reg.size() == OperandSize::Word => Dest::Reg(reg),
+            Src::Mem(mem) if mem.size == OperandSize::Word => Dest::Mem(mem),
+            _ => return Err(self.invalid()),
+        };
+        self.opcode = vec![opcode];
+        self.rm(segment as u8, rm)
+    }
+
+    fn push(&mut self, src: Src, size: OperandSize) -> Result<(), EncodeError> {
+        match (src, size) {
+            (Src::Imm(value), _) => {
+                if let Ok(value) = imm8(value, OperandSize::Qword) {
+                    self.opcode = vec![0x6a];
+                    self.immediate = vec![value];
+                } else {
+                    self.opcode = vec![0x68];
+                    self.immediate = imm32(value)?.to_vec();
+                }
+                Ok(())
+            }
+            (_, OperandSize::Byte | OperandSize::Dword) => Err(self.invalid()),
+            (Src::Reg(reg), size) => {
+                self.operand_size = size == OperandSize::Word;
+                let r = self.register(reg, REX_B);
+                self.opcode = vec![0x50 | r];
+                Ok(())
+            }
+            (Src::Mem(mem), size) => {
+                self.operand_size = size == OperandSize::Word;
+                self.opcode = vec![0xff];
+                self.memory(6, mem)
+            }
+        }
+    }
+
+    fn pop(&mut self, dest: Dest, size: OperandSize) -> Result<(), EncodeError> {
+        if matches!(size, OperandSize::Byte | OperandSize::Dword) {
+            return Err(self.invalid());
+        }
+        self.operand_size = size == OperandSize::Word;
+        match dest {
+            Dest::Reg(reg) => {
+                let r = self.register(reg, REX_B);
+                self.opcode = vec![0x58 | r];
+                Ok(())
+            }
+            Dest::Mem(mem) => {
+                self.opcode = vec![0x8f];
+                self.memory(0, mem)
+            }
+        }
+    }
+
+    /// Encodes ADD, OR, AND, SUB, XOR and CMP
+    fn alu(&mut self, op: u8, dest: Dest, src: Src, size: OperandSize) -> Result<(), EncodeError> {
+        self.check_size(src, size)?;
+        match (dest, src) {
+            (dest, Src::Reg(reg)) => self.rm_reg(op << 3, dest, reg, size),
+            (Dest::Reg(reg), Src::Mem(_)) => {
+                self.reg_rm(&[Self::opcode_w(op << 3 | 0b10, size)], reg, src, size)
+            }
+            (dest, Src::Imm(value)) => {
+                if size != OperandSize::Byte {
+                    if let Ok(value) = imm8(value, size) {
+                        self.size(size);
+                        self.opcode = vec![0x83];
+                        self.immediate = vec![value];
+                        return self.rm(op, dest);
+                    }
+                }
+                if let Dest::Reg(Reg::Gpr(0, _)) = dest {
+                    // the accumulator short form saves the ModRM byte
+                    self.size(size);
+                    self.opcode = vec![Self::opcode_w(op << 3 | 0b100, size)];
+                } else {
+                    self.group(0x80, op, dest, size)?;
+                }
+                self.immediate(value, size)
+            }
+            _ => Err(self.invalid()),
+        }
+    }
+
+    fn test(&mut self, dest: Dest, src: Src, size: OperandSize) -> Result<(), EncodeError> {
+        self.check_size(src, size)?;
+        match (dest, src) {
+            (dest, Src::Reg(reg)) => self.rm_reg(0x84, dest, reg, size),
+            // TEST is commutative and has no r, r/m form
+            (Dest::Reg(reg), Src::Mem(mem)) => self.rm_reg(0x84, Dest::Mem(mem), reg, size),
+            (Dest::Reg(Reg::Gpr(0, _)), Src::Imm(value)) => {
+                self.size(size);
+                self.opcode = vec![Self::opcode_w(0xa8, size)];
+                self.immediate(value, size)
+            }
+            (dest, Src::Imm(value)) => {
+                self.group(0xf6, 0, dest, size)?;
+                self.immediate(value, size)
+            }
+            _ => Err(self.invalid()),
+        }
+    }
+
+    /// Encodes a relative branch, using `short` with an 8-bit displacement if
+    /// the target is in reach
+    fn branch(&mut self, short: &[u8], near: &[u8], target: u64) -> Result<(), EncodeError> {
+        if !short.is_empty() {
+            let end = self.address.wrapping_add(short.len() as u64 + 1);
+            if let Ok(rel) = i8::try_from(target.wrapping_sub(end) as i64) {
+                self.opcode = short.to_vec();
+                self.immediate = vec![rel as u8];
+                return Ok(());
+            }
+        }
+
+        let end = self.address.wrapping_add(near.len() as u64 + 4);
+        let rel = i32::try_from(target.wrapping_sub(end) as i64).map_err(|_| {
+            EncodeError::BranchOutOfRange {
+                address: self.address,
+                target,
+            }
+        })?;
+        self.opcode = near.to_vec();
+        self.immediate = rel.to_le_bytes().to_vec();
+        Ok(())
+    }
+
+    /// Encodes JMP and CALL through a 64-bit register or memory operand
+    fn indirect_branch(&mut self, op: u8, target: Src) -> Result<(), EncodeError> {
+        let dest = match target {
+            Src::Reg(reg) => Dest::Reg(reg),
+            Src::Mem(mem) => Dest::Mem(mem),
+            Src::Imm(_) => return Err(self.invalid()),
+        };
+        if dest.size() != OperandSize::Qword {
+            return Err(self.invalid());
+        }
+        self.opcode = vec![0xff];
+        self.rm(op, dest)
+    }
+}
+
+/// Truncates an immediate to `size`, it must be representable either
+/// zero-extended or sign-extended
+fn truncate(value: u64, size: OperandSize) -> Result<u64, EncodeError> {
+    let truncated = value & size.mask();
+    if truncated == value || size.sign_extend(truncated) as u64 == value {
+        Ok(truncated)
+    } else {
+        Err(EncodeError::OutOfRange {
+            value,
+            bits: size.bits(),
+        })
+    }
+}
+
+/// Returns the 8-bit immediate sign-extended to `value` in an operation of `size`
+fn imm8(value: u64, size: OperandSize) -> Result<u8, EncodeError> {
+    let value = size.sign_extend(truncate(value, size)?);
+    i8::try_from(value)
+        .map(|value| value as u8)
+        .map_err(|_| EncodeError::OutOfRange {
+            value: value as u64,
+            bits: 8,
+        })
+}
+
+/// Returns the 32-bit immediate sign-extended to the 64-bit `value`
+fn imm32(value: u64) -> Result<[u8; 4], EncodeError> {
+    i32::try_from(value as i64)
+        .map(i32::to_le_bytes)
+        .map_err(|_| EncodeError::OutOfRange { value, bits: 32 })
+}
+
+fn disp32(displacement: i64) -> Result<[u8; 4], EncodeError> {
+    i32::try_from(displacement)
+        .map(i32::to_le_bytes)
+        .map_err(|_| EncodeError::OutOfRange {
+            value: displacement as u64,
+            bits: 32,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoder::decode;
+    use crate::instruction::Condition;
+    use cpu::device::DRAM;
+    use cpu::Addressable;
+    use OperandSize::*;
+
+    const ADDRESS: u64 = 0x1000;
+
+    fn round_trip(instr: Instr) -> Vec<u8> {
+        let bytes = encode(&instr, ADDRESS).unwrap_or_else(|e| panic!("{instr}: {e}"));
+        let mut dram = DRAM::new(0, 1 << 16);
+        dram.alloc(ADDRESS as usize, bytes.len()).unwrap();
+        dram.write_bytes(ADDRESS as usize, &bytes).unwrap();
+        let (decoded, length) = decode(&dram, ADDRESS).unwrap_or_else(|e| panic!("{instr}: {e}"));
+        assert_eq!(decoded, instr, "{instr} encoded as {bytes:02x?}");
+        assert_eq!(length, bytes.len(), "{instr}");
+        bytes
+    }
+
+    fn gpr(index: u8, size: OperandSize) -> Dest {
+        Dest::Reg(Reg::Gpr(index, size))
+    }
+
+    fn mem(addressing: Addressing, size: OperandSize) -> Dest {
+        Dest::Mem(Memory::new(addressing, size))
+    }
+
+    #[test]
+    fn test_shortest_encoding() {
+        // add rax, 1
+        assert_eq!(
+            round_trip(Instr::Add(gpr(0, Qword), Src::Imm(1))),
+            [0x48, 0x83, 0xc0, 0x01]
+        );
+        // add eax, 0x1000
+        assert_eq!(
+            round_trip(Instr::Add(gpr(0, Dword), Src::Imm(0x1000))),
+            [0x05, 0x00, 0x10, 0x00, 0x00]
+        );
+        // mov rax, -1 sign-extends a 32-bit immediate
+        assert_eq!(
+            round_trip(Instr::Mov(gpr(0, Qword), Src::Imm(u64::MAX))),
+            [0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]
+        );
+        // mov r8, imm64
+        assert_eq!(
+            round_trip(Instr::Mov(gpr(8, Qword), Src::Imm(0x0123456789abcdef))),
+            [0x49, 0xb8, 0xef, 0xcd, 0xab, 0x89, 0x67, 0x45, 0x23, 0x01]
+        );
+        // mov sil, ah cannot be encoded
+        assert_eq!(
+            encode(
+                &Instr::Mov(gpr(6, Byte), Src::Reg(Reg::HighByte(0))),
+                ADDRESS
+            ),
+            Err(EncodeError::InvalidOperands(Instr::Mov(
+                gpr(6, Byte),
+                Src::Reg(Reg::HighByte(0))
+            )))
+        );
+        // jmp $ and a near jcc
+        assert_eq!(round_trip(Instr::Jmp(Src::Imm(ADDRESS))), [0xeb, 0xfe]);
+        assert_eq!(
+            round_trip(Instr::Jcc(Condition::Ne, Src::Imm(ADDRESS + 0x1006))),
+            [0x0f, 0x85, 0x00, 0x10, 0x00, 0x00]
+        );
+        // [rbp] needs an 8-bit displacement, [r12] a SIB byte
+        assert_eq!(
+            round_trip(Instr::Inc(mem(Addressing::Base(5), Dword))),
+            [0xff, 0x45, 0x00]
+        );
+        assert_eq!(
+            round_trip(Instr::Inc(mem(Addressing::Base(12), Dword))),
+            [0x41, 0xff, 0x04, 0x24]
+        );
+        // segment registers are only moved to and from 16-bit operands
+        let ds = Reg::Segment(Segment::Ds);
+        assert_eq!(
+            round_trip(Instr::Mov(Dest::Reg(ds), Src::Reg(Reg::Gpr(0, Word)))),
+            [0x8e, 0xd8]
+        );
+        assert_eq!(
+            round_trip(Instr::Mov(mem(Addressing::Base(3), Word), Src::Reg(ds))),
+            [0x8c, 0x1b]
+        );
+        assert!(encode(&Instr::Add(Dest::Reg(ds), Src::Imm(1)), ADDRESS).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let addressings = [
+            Addressing::Displacement(0x2000),
+            Addressing::RipRelative(-0x10),
+            Addressing::Base(4),
+            Addressing::Base(13),
+            Addressing::BaseIndex(0, 9),
+            Addressing::BaseDisplacement(5, -8),
+            Addressing::BaseDisplacement(12, 0x1234),
+            Addressing::BaseIndexDisplacement(3, 15, 0x7f),
+            Addressing::BaseIndexScale(7, 1, 8),
+            Addressing::IndexScaleDisplacement(6, 4, 0x40),
+            Addressing::BaseIndexScaleDisplacement(13, 12, 2, -0x80),
+        ];
+        let sizes = [Byte, Word, Dword, Qword];
+        let immediates = [0, 1, u64::MAX, 0x7f, 0x80, 0x7fff, (-0x8000i64) as u64];
+
+        let mut instrs = Vec::new();
+        for size in sizes {
+            for index in 0..16 {
+                let reg = gpr(index, size);
+                let other = Src::Reg(Reg::Gpr(15 - index, size));
+                instrs.extend([
+                    Instr::Mov(reg, other),
+                    Instr::Add(reg, other),
+                    Instr::Cmp(reg, other),
+                    Instr::Test(reg, other),
+                    Instr::Inc(reg),
+                    Instr::Neg(reg),
+                ]);
+                for imm in immediates {
+                    let imm = size.sign_extend(imm) as u64;
+                    instrs.extend([
+                        Instr::Sub(reg, Src::Imm(imm)),
+                        Instr::Xor(reg, Src::Imm(imm)),
+                        Instr::Test(reg, Src::Imm(imm)),
+                    ]);
+                }
+            }
+            for addressing in addressings {
+                let memory = mem(addressing, size);
+                instrs.extend([
+                    Instr::Mov(memory, Src::Reg(Reg::Gpr(2, size))),
+                    Instr::Mov(gpr(11, size), memory.into()),
+                    Instr::Mov(memory, Src::Imm(size.sign_extend(0x5a) as u64)),
+                    Instr::And(memory, Src::Imm(u64::MAX)),
+                    Instr::Or(gpr(0, size), memory.into()),
+                    Instr::Not(memory),
+                    Instr::IDiv(memory.into()),
+                    Instr::Dec(Dest::Mem(
+                        Memory::new(addressing, size).with_segment(Some(Segment::Gs)),
+                    )),
+                ]);
+                if size != Byte {
+                    instrs.extend([
+                        Instr::IMul(gpr(3, size), memory.into()),
+                        Instr::Cmovcc(Condition::Ge, gpr(9, size), memory.into()),
+                    ]);
+                }
+            }
+        }
+        for index in 0..4 {
+            instrs.push(Instr::Mov(
+                Dest::Reg(Reg::HighByte(index)),
+                Src::Reg(Reg::Gpr(index, Byte)),
+            ));
+        }
+        for code in 0..16 {
+            let condition = Condition::from_code(code);
+            instrs.extend([
+                Instr::Setcc(condition, gpr(code, Byte)),
+                Instr::Jcc(condition, Src::Imm(ADDRESS - 0x80)),
+                Instr::Jcc(condition, Src::Imm(ADDRESS + 0x10_0000)),
+            ]);
+        }
+        for index in 0..16 {
+            instrs.extend([
+                Instr::Push(Src::Reg(Reg::Gpr(index, Qword))),
+                Instr::Pop(gpr(index, Word)),
+                Instr::Jmp(Src::Reg(Reg::Gpr(index, Qword))),
+                Instr::Call(Src::Mem(Memory::new(Addressing::Base(index), Qword))),
+            ]);
+        }
+        instrs.extend([
+            Instr::Push(Src::Imm(u64::MAX)),
+            Instr::Push(Src::Imm(0x12345678)),
+            Instr::Pop(mem(Addressing::BaseDisplacement(4, 8), Qword)),
+            Instr::Call(Src::Imm(0)),
+            Instr::Jmp(Src::Imm(ADDRESS + 0x81)),
+            Instr::Ret,
+            Instr::Nop,
+            Instr::Hlt,
+        ]);
+
+        for instr in instrs {
+            round_trip(instr);
+        }
+    }
+}
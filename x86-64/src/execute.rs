@@ -0,0 +1,932 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+
+use cpu::bus::Bus;
+use cpu::{AccessType, Addressable, Endianness, MemoryAccessError};
+use thiserror::Error;
+
+use crate::decoder::{self, CodeSize, DecodeError};
+use crate::flags::{self, FlagUpdate};
+use crate::instruction::{
+    Addressing, Condition, Dest, Instr, Memory, OperandSize, Reg, Segment, Src,
+};
+use crate::paging::{PageFaultErrorCode, PagingError, PagingMode, MMU, PAGE_SIZE};
+use crate::Cpu;
+
+/// Faults raised while executing an instruction
+#[derive(Debug, Error)]
+pub enum Exception {
+    /// #DE, raised by division by zero or a quotient that does not fit
+    #[error("Divide error")]
+    DivideError,
+
+    /// #UD, raised when the instruction cannot be decoded
+    #[error("Invalid opcode: {0}")]
+    InvalidOpcode(DecodeError),
+
+    /// #GP, raised by non-canonical addresses
+    #[error("General protection fault at {address:#x}")]
+    GeneralProtection { address: u64 },
+
+    /// #PF, raised when the page translation is missing or forbids the access
+    #[error("Page fault at {address:#x} ({error_code:?})")]
+    PageFault {
+        address: u64,
+        error_code: PageFaultErrorCode,
+    },
+
+    /// The memory access could not be completed by any device
+    #[error("Memory access fault: {0}")]
+    Memory(#[from] MemoryAccessError),
+}
+
+impl From<PagingError> for Exception {
+    fn from(error: PagingError) -> Self {
+        match error {
+            PagingError::PageFault {
+                address,
+                error_code,
+            } => Exception::PageFault {
+                address,
+                error_code,
+            },
+            PagingError::NonCanonical { address } => Exception::GeneralProtection { address },
+            PagingError::Memory(error) => Exception::Memory(error),
+        }
+    }
+}
+
+impl From<DecodeError> for Exception {
+    fn from(error: DecodeError) -> Self {
+        match error {
+            DecodeError::Memory(error) => Exception::Memory(error),
+            error => Exception::InvalidOpcode(error),
+        }
+    }
+}
+
+/// The outcome of a successfully executed instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Flow {
+    Continue,
+    Halt,
+}
+
+impl Cpu {
+    /// Fetches, decodes and executes the instruction at RIP
+    ///
+    /// On a fault RIP is left pointing at the faulting instruction.
+    pub(crate) fn step_instruction(&mut self) -> Result<Flow, Exception> {
+        let rip = self.registers.rip();
+        let (instr, length) = self.fetch(rip)?;
+        let next = rip.wrapping_add(length as u64) & self.pointer_size().mask();
+        self.registers.write_rip(next);
+
+        let result = self.execute(instr);
+        if result.is_err() {
+            self.registers.write_rip(rip);
+        }
+        result
+    }
+
+    fn execute(&mut self, instr: Instr) -> Result<Flow, Exception> {
+        let size = instr.operand_size();
+        match instr {
+            Instr::Mov(dest, src) => {
+                let value = self.read_src(src, size)?;
+                self.write_dest(dest, value)?;
+            }
+            Instr::Push(src) => {
+                // immediates are pushed with the width of the stack pointer
+                let size = match src {
+                    Src::Imm(_) => self.pointer_size(),
+                    _ => size,
+                };
+                let value = self.read_src(src, size)?;
+                self.push(value, size)?;
+            }
+            Instr::Pop(dest) => {
+                let value = self.pop(size)?;
+                self.write_dest(dest, value)?;
+            }
+
+            Instr::Add(dest, src) => {
+                let (a, b) = (self.read_dest(dest)?, self.read_src(src, size)?);
+                let (result, update) = flags::add(a, b, size);
+                self.update_flags(update);
+                self.write_dest(dest, result)?;
+            }
+            Instr::Sub(dest, src) => {
+                let (a, b) = (self.read_dest(dest)?, self.read_src(src, size)?);
+                let (result, update) = flags::sub(a, b, size);
+                self.update_flags(update);
+                self.write_dest(dest, result)?;
+            }
+            Instr::Cmp(dest, src) => {
+                let (a, b) = (self.read_dest(dest)?, self.read_src(src, size)?);
+                let (_, update) = flags::sub(a, b, size);
+                self.update_flags(update);
+            }
+            Instr::Inc(dest) => {
+                let (result, update) = flags::inc(self.read_dest(dest)?, size);
+                self.update_flags(update);
+                self.write_dest(dest, result)?;
+            }
+            Instr::Dec(dest) => {
+                let (result, update) = flags::dec(self.read_dest(dest)?, size);
+                self.update_flags(update);
+                self.write_dest(dest, result)?;
+            }
+            Instr::Neg(dest) => {
+                let (result, update) = flags::neg(self.read_dest(dest)?, size);
+                self.update_flags(update);
+                self.write_dest(dest, result)?;
+            }
+            Instr::IMul(dest, src) => {
+                let (a, b) = (self.read_dest(dest)?, self.read_src(src, size)?);
+                let (result, update) = flags::imul(a, b, size);
+                self.update_flags(update);
+                self.write_dest(dest, result)?;
+            }
+            Instr::IDiv(src) => {
+                let divisor = size.sign_extend(self.read_src(src, size)?) as i128;
+                if divisor == 0 {
+                    return Err(Exception::DivideError);
+                }
+
+                // the dividend is AX for byte operands and DX:AX, EDX:EAX or RDX:RAX otherwise
+                let bits = size.bits();
+                let dividend = match size {
+                    OperandSize::Byte => self.registers.gr_sized(0, OperandSize::Word) as u128,
+                    _ => {
+                        let high = self.registers.gr_sized(2, size) as u128;
+                        let low = self.registers.gr_sized(0, size) as u128;
+                        (high << bits) | low
+                    }
+                };
+                let shift = 128 - 2 * bits;
+                let dividend = ((dividend << shift) as i128) >> shift;
+
+                // MIN / -1 overflows even an i128 and raises #DE like any other overflow
+                let (Some(quotient), Some(remainder)) =
+                    (dividend.checked_div(divisor), dividend.checked_rem(divisor))
+                else {
+                    return Err(Exception::DivideError);
+                };
+                let limit = 1i128 << (bits - 1);
+                if quotient >= limit || quotient < -limit {
+                    return Err(Exception::DivideError);
+                }
+
+                let (quotient, remainder) = (quotient as u64, remainder as u64);
+                match size {
+                    OperandSize::Byte => self.registers.write_gr_sized(
+                        0,
+                        OperandSize::Word,
+                        (remainder & 0xff) << 8 | (quotient & 0xff),
+                    ),
+                    _ => {
+                        self.registers.write_gr_sized(0, size, quotient);
+                        self.registers.write_gr_sized(2, size, remainder);
+                    }
+                }
+                self.update_flags(flags::idiv(quotient, size));
+            }
+
+            Instr::And(dest, src) => {
+                let result = self.read_dest(dest)? & self.read_src(src, size)?;
+                let (result, update) = flags::logic(result, size);
+                self.update_flags(update);
+                self.write_dest(dest, result)?;
+            }
+            Instr::Or(dest, src) => {
+                let result = self.read_dest(dest)? | self.read_src(src, size)?;
+                let (result, update) = flags::logic(result, size);
+                self.update_flags(update);
+                self.write_dest(dest, result)?;
+            }
+            Instr::Xor(dest, src) => {
+                let result = self.read_dest(dest)? ^ self.read_src(src, size)?;
+                let (result, update) = flags::logic(result, size);
+                self.update_flags(update);
+                self.write_dest(dest, result)?;
+            }
+            Instr::Not(dest) => {
+                let result = !self.read_dest(dest)?;
+                self.write_dest(dest, result)?;
+            }
+            Instr::Test(dest, src) => {
+                let result = self.read_dest(dest)? & self.read_src(src, size)?;
+                let (_, update) = flags::logic(result, size);
+                self.update_flags(update);
+            }
+            Instr::Setcc(condition, dest) => {
+                let value = self.condition(condition) as u64;
+                self.write_dest(dest, value)?;
+            }
+            Instr::Cmovcc(condition, dest, src) => {
+                // the source is read even if the condition does not hold
+                let value = self.read_src(src, size)?;
+                if self.condition(condition) {
+                    self.write_dest(dest, value)?;
+                } else if size == OperandSize::Dword {
+                    // a 32-bit destination register is zero-extended regardless
+                    let value = self.read_dest(dest)?;
+                    self.write_dest(dest, value)?;
+                }
+            }
+
+            Instr::Jmp(target) => self.jump_if(true, target)?,
+            Instr::Jcc(condition, target) => self.jump_if(self.condition(condition), target)?,
+            Instr::Call(target) => {
+                let size = self.pointer_size();
+                let target = self.read_src(target, size)?;
+                self.push(self.registers.rip(), size)?;
+                self.registers.write_rip(target);
+            }
+            Instr::Ret => {
+                let target = self.pop(self.pointer_size())?;
+                self.registers.write_rip(target);
+            }
+
+            Instr::Nop => {}
+            Instr::Hlt => return Ok(Flow::Halt),
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    /// Returns the default operand and address size of the code, which follows the paging mode
+    fn code_size(&self) -> CodeSize {
+        match self.mmu.paging_mode() {
+            PagingMode::Real => CodeSize::Bits16,
+            PagingMode::Protected => CodeSize::Bits32,
+            PagingMode::Long | PagingMode::LongLA57 => CodeSize::Bits64,
+        }
+    }
+
+    /// Returns the width of the instruction and stack pointers
+    fn pointer_size(&self) -> OperandSize {
+        match self.code_size() {
+            CodeSize::Bits16 => OperandSize::Word,
+            CodeSize::Bits32 => OperandSize::Dword,
+            CodeSize::Bits64 => OperandSize::Qword,
+        }
+    }
+
+    /// Returns the base address of a segment
+    ///
+    /// In real mode the base is the selector shifted by four bits. Otherwise
+    /// segmentation is flat and only FS and GS have a non-zero base.
+    fn segment_base(&self, segment: Segment) -> u64 {
+        match (self.mmu.paging_mode(), segment) {
+            (PagingMode::Real, segment) => (self.registers.segment(segment) as u64) << 4,
+            (_, Segment::Fs) => self.registers.fs_base(),
+            (_, Segment::Gs) => self.registers.gs_base(),
+            _ => 0,
+        }
+    }
+
+    fn condition(&self, condition: Condition) -> bool {
+        condition.evaluate(*self.registers.rflags())
+    }
+
+    fn update_flags(&mut self, update: FlagUpdate) {
+        update.apply(self.registers.rflags_mut(), self.undefined_flags);
+    }
+
+    fn jump_if(&mut self, condition: bool, target: Src) -> Result<(), Exception> {
+        if condition {
+            let target = self.read_src(target, self.pointer_size())?;
+            self.registers.write_rip(target);
+        }
+        Ok(())
+    }
+
+    /// Pushes `value` at SS:SP, the stack pointer wraps around at its width
+    fn push(&mut self, value: u64, size: OperandSize) -> Result<(), Exception> {
+        let width = self.pointer_size();
+        let sp = self
+            .registers
+            .gr_sized(4, width)
+            .wrapping_sub(size.bytes() as u64)
+            & width.mask();
+        let address = self.segment_base(Segment::Ss).wrapping_add(sp);
+        self.write_memory(address, size, value)?;
+        self.registers.write_gr_sized(4, width, sp);
+        Ok(())
+    }
+
+    fn pop(&mut self, size: OperandSize) -> Result<u64, Exception> {
+        let width = self.pointer_size();
+        let sp = self.registers.gr_sized(4, width);
+        let address = self.segment_base(Segment::Ss).wrapping_add(sp);
+        let value = self.read_memory(address, size)?;
+        self.registers
+            .write_gr_sized(4, width, sp.wrapping_add(size.bytes() as u64));
+        Ok(value)
+    }
+
+    /// Computes the linear address of a memory operand
+    ///
+    /// RIP-relative operands are resolved against the current RIP, which
+    /// already points to the next instruction. The offset wraps around at the
+    /// address size before the segment base is added. Operands based on RSP
+    /// or RBP default to the SS segment, all others to DS.
+    fn effective_address(&self, mem: Memory) -> u64 {
+        let (base_reg, index, displacement) = mem.addressing.components();
+        let base = match mem.addressing {
+            Addressing::RipRelative(_) => self.registers.rip(),
+            _ => base_reg.map_or(0, |base| self.registers.gr(base)),
+        };
+        let index = index.map_or(0, |(index, scale)| {
+            self.registers.gr(index).wrapping_mul(scale as u64)
+        });
+        let offset =
+            base.wrapping_add(index).wrapping_add_signed(displacement) & mem.address_size.mask();
+        let segment = mem.segment.unwrap_or(match base_reg {
+            Some(4 | 5) => Segment::Ss,
+            _ => Segment::Ds,
+        });
+        self.segment_base(segment).wrapping_add(offset)
+    }
+
+    /// Fetches and decodes the instruction at `rip`
+    ///
+    /// In real mode `rip` is the offset into the code segment. With paging
+    /// enabled the page following the first one is only translated if the
+    /// instruction continues into it.
+    fn fetch(&mut self, rip: u64) -> Result<(Instr, usize), Exception> {
+        let code_size = self.code_size();
+        if code_size == CodeSize::Bits16 {
+            let segment = CodeSegment {
+                bus: &self.bus,
+                mmu: &self.mmu,
+                base: self.segment_base(Segment::Cs),
+            };
+            return Ok(decoder::decode_sized(&segment, rip, code_size)?);
+        }
+        if !self.mmu.enabled() && self.mmu.a20() {
+            return Ok(decoder::decode_sized(&self.bus, rip, code_size)?);
+        }
+
+        let mut pages = vec![self.translate(rip, AccessType::Execute)? & !(PAGE_SIZE - 1)];
+        loop {
+            let window = FetchWindow {
+                bus: &self.bus,
+                rip,
+                pages: &pages,
+                missing: Cell::new(None),
+            };
+            let result = decoder::decode_sized(&window, rip, code_size);
+            match window.missing.get() {
+                Some(address) if result.is_err() => {
+                    let page = self.translate(address, AccessType::Execute)?;
+                    pages.push(page & !(PAGE_SIZE - 1));
+                }
+                _ => return Ok(result?),
+            }
+        }
+    }
+
+    /// Translates a linear address, recording the address of page faults in CR2
+    fn translate(&mut self, address: u64, access: AccessType) -> Result<u64, Exception> {
+        let result = self
+            .mmu
+            .translate(&mut self.bus, address, access, self.privilege);
+        if let Err(PagingError::PageFault { address, .. }) = result {
+            self.mmu.write_cr2(address);
+        }
+        Ok(result?)
+    }
+
+    /// Translates the pages covered by an access of `size` bytes at `address`
+    ///
+    /// Returns the physical address and the length of the part of the access
+    /// in every page. All pages are translated before any of them is accessed.
+    fn translate_access(
+        &mut self,
+        address: u64,
+        size: usize,
+        access: AccessType,
+    ) -> Result<Vec<(usize, usize)>, Exception> {
+        if !self.mmu.enabled() && self.mmu.a20() {
+            return Ok(vec![(address as usize, size)]);
+        }
+
+        let mut pieces = Vec::with_capacity(2);
+        let mut offset = 0;
+        while offset < size {
+            let linear = address.wrapping_add(offset as u64);
+            let len = (size - offset).min((PAGE_SIZE - linear % PAGE_SIZE) as usize);
+            pieces.push((self.translate(linear, access)? as usize, len));
+            offset += len;
+        }
+        Ok(pieces)
+    }
+
+    fn read_memory(&mut self, address: u64, size: OperandSize) -> Result<u64, Exception> {
+        let pieces = self.translate_access(address, size.bytes(), AccessType::Read)?;
+        if let [(address, size)] = pieces[..] {
+            return Ok(self
+                .bus
+                .read_uint(address, size, Endianness::LittleEndian)?);
+        }
+
+        let mut bytes = [0; 8];
+        let mut offset = 0;
+        for (address, len) in pieces {
+            bytes[offset..offset + len].copy_from_slice(&self.bus.read_bytes(address, len)?);
+            offset += len;
+        }
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write_memory(
+        &mut self,
+        address: u64,
+        size: OperandSize,
+        value: u64,
+    ) -> Result<(), Exception> {
+        let bytes = value.to_le_bytes();
+        let mut offset = 0;
+        for (address, len) in self.translate_access(address, size.bytes(), AccessType::Write)? {
+            self.bus
+                .write_bytes(address, &bytes[offset..offset + len])?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    fn read_reg(&self, reg: Reg) -> u64 {
+        match reg {
+            Reg::Gpr(index, size) => self.registers.gr_sized(index, size),
+            Reg::HighByte(index) => self.registers.high_byte(index) as u64,
+            Reg::Segment(segment) => self.registers.segment(segment) as u64,
+        }
+    }
+
+    fn write_reg(&mut self, reg: Reg, value: u64) {
+        match reg {
+            Reg::Gpr(index, size) => self.registers.write_gr_sized(index, size, value),
+            Reg::HighByte(index) => self.registers.write_high_byte(index, value as u8),
+            Reg::Segment(segment) => self.registers.write_segment(segment, value as u16),
+        }
+    }
+
+    /// Reads a source operand, immediates are truncated to `size`
+    fn read_src(&mut self, src: Src, size: OperandSize) -> Result<u64, Exception> {
+        match src {
+            Src::Reg(reg) => Ok(self.read_reg(reg)),
+            Src::Mem(mem) => self.read_memory(self.effective_address(mem), mem.size),
+            Src::Imm(value) => Ok(value & size.mask()),
+        }
+    }
+
+    fn read_dest(&mut self, dest: Dest) -> Result<u64, Exception> {
+        self.read_src(dest.into(), dest.size())
+    }
+
+    fn write_dest(&mut self, dest: Dest, value: u64) -> Result<(), Exception> {
+        match dest {
+            Dest::Reg(reg) => {
+                self.write_reg(reg, value);
+                Ok(())
+            }
+            Dest::Mem(mem) => self.write_memory(self.effective_address(mem), mem.size, value),
+        }
+    }
+}
+
+/// The instruction bytes at RIP as seen through the page translation
+struct FetchWindow<'a> {
+    bus: &'a Bus,
+    rip: u64,
+    /// The physical addresses of the translated pages, starting with the page of RIP
+    pages: &'a [u64],
+    /// The first address read beyond the translated pages
+    missing: Cell<Option<u64>>,
+}
+
+impl FetchWindow<'_> {
+    fn physical(&self, address: usize) -> Result<usize, MemoryAccessError> {
+        let address = address as u64;
+        let page = (address / PAGE_SIZE).wrapping_sub(self.rip / PAGE_SIZE);
+        match self.pages.get(page as usize) {
+            Some(physical) => Ok((physical + address % PAGE_SIZE) as usize),
+            None => {
+                self.missing.set(Some(address));
+                Err(MemoryAccessError::AddressNotMapped)
+            }
+        }
+    }
+}
+
+impl Addressable for FetchWindow<'_> {
+    fn read_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        self.bus.read_byte(self.physical(address)?)
+    }
+
+    fn fetch_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        self.bus.fetch_byte(self.physical(address)?)
+    }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, MemoryAccessError> {
+        (address..address + size)
+            .map(|address| self.read_byte(address))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Cow::Owned)
+    }
+
+    fn write_byte(&mut self, address: usize, _value: u8) -> Result<(), MemoryAccessError> {
+        Err(MemoryAccessError::WriteProtected { address })
+    }
+
+    fn write_bytes(&mut self, address: usize, _value: &[u8]) -> Result<(), MemoryAccessError> {
+        Err(MemoryAccessError::WriteProtected { address })
+    }
+}
+
+/// The code segment in real mode
+///
+/// Reads at an offset are mapped to CS:IP, the offset wraps around at 64 KiB.
+struct CodeSegment<'a> {
+    bus: &'a Bus,
+    mmu: &'a MMU,
+    base: u64,
+}
+
+impl CodeSegment<'_> {
+    fn physical(&self, address: usize) -> usize {
+        let linear = self.base.wrapping_add(address as u64 & 0xffff);
+        self.mmu.apply_a20(linear) as usize
+    }
+}
+
+impl Addressable for CodeSegment<'_> {
+    fn read_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        self.bus.read_byte(self.physical(address))
+    }
+
+    fn fetch_byte(&self, address: usize) -> Result<u8, MemoryAccessError> {
+        self.bus.fetch_byte(self.physical(address))
+    }
+
+    fn read_bytes(&self, address: usize, size: usize) -> Result<Cow<'_, [u8]>, MemoryAccessError> {
+        (address..address + size)
+            .map(|address| self.read_byte(address))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Cow::Owned)
+    }
+
+    fn write_byte(&mut self, address: usize, _value: u8) -> Result<(), MemoryAccessError> {
+        Err(MemoryAccessError::WriteProtected { address })
+    }
+
+    fn write_bytes(&mut self, address: usize, _value: &[u8]) -> Result<(), MemoryAccessError> {
+        Err(MemoryAccessError::WriteProtected { address })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::PagingMode;
+    use crate::register::Flags;
+    use cpu::device::DRAM;
+    use cpu::{Cpu as _, StopReason};
+
+    fn cpu_with_program(program: &[u8]) -> Cpu {
+        let mut dram = DRAM::new(0, 1 << 16);
+        dram.alloc(0x1000, program.len()).unwrap();
+        dram.write_bytes(0x1000, program).unwrap();
+        dram.alloc(0x8000, 0x1000).unwrap();
+
+        let mut cpu = Cpu::new(PagingMode::Long);
+        cpu.add_device(Box::new(dram)).unwrap();
+        cpu.registers_mut().write_rip(0x1000);
+        cpu.registers_mut().write_rsp(0x9000);
+        cpu
+    }
+
+    #[test]
+    fn test_run_loop() {
+        // sum the numbers 1 to 10 into rax through a call
+        let mut cpu = cpu_with_program(&[
+            0x48, 0x31, 0xc0, // xor rax, rax
+            0x48, 0xc7, 0xc1, 0x0a, 0x00, 0x00, 0x00, // mov rcx, 10
+            0xe8, 0x01, 0x00, 0x00, 0x00, // call add
+            0xf4, // hlt
+            0x48, 0x01, 0xc8, // add: add rax, rcx
+            0x48, 0xff, 0xc9, // dec rcx
+            0x75, 0xf8, // jnz add
+            0xc3, // ret
+        ]);
+        assert_eq!(cpu.run(), StopReason::Halted);
+        assert!(cpu.halted());
+        assert_eq!(cpu.registers().rax(), 55);
+        assert_eq!(cpu.registers().rsp(), 0x9000);
+        assert_eq!(cpu.registers().rip(), 0x1010);
+        assert_eq!(cpu.step(), Some(StopReason::Halted));
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut cpu = cpu_with_program(&[
+            0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff, // mov rax, -1
+            0x48, 0x83, 0xf8, 0x01, // cmp rax, 1
+            0x0f, 0x97, 0xc1, // seta cl
+            0x0f, 0x9c, 0xc2, // setl dl
+            0x48, 0xc7, 0xc3, 0x07, 0x00, 0x00, 0x00, // mov rbx, 7
+            0x48, 0x0f, 0x42, 0xd8, // cmovb rbx, rax
+            0x48, 0x0f, 0x47, 0xf3, // cmova rsi, rbx
+            0x72, 0x01, // jb +1
+            0xf4, // hlt
+            0xeb, 0xfe, // jmp $
+        ]);
+        cpu.registers_mut().write_rcx(0x1234_5600);
+        assert_eq!(cpu.run(), StopReason::Halted);
+        assert_eq!(cpu.registers().rcx(), 0x1234_5601);
+        assert_eq!(cpu.registers().dl(), 1);
+        assert_eq!(cpu.registers().rbx(), 7);
+        assert_eq!(cpu.registers().rsi(), 7);
+    }
+
+    #[test]
+    fn test_operand_sizes() {
+        let mut cpu = cpu_with_program(&[
+            0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff, // mov rax, -1
+            0xb4, 0x12, // mov ah, 0x12
+            0x48, 0x89, 0xc3, // mov rbx, rax
+            0x89, 0xc1, // mov ecx, eax
+            0x66, 0xb8, 0x64, 0x00, // mov ax, 100
+            0xb2, 0x07, // mov dl, 7
+            0xf6, 0xfa, // idiv dl
+            0x80, 0xc2, 0xff, // add dl, 0xff
+            0xf4, // hlt
+        ]);
+        assert_eq!(cpu.run(), StopReason::Halted);
+        assert_eq!(cpu.registers().rbx(), 0xffff_ffff_ffff_12ff);
+        assert_eq!(cpu.registers().rcx(), 0xffff_12ff);
+        assert_eq!(cpu.registers().rax(), 0xffff_ffff_ffff_020e);
+        assert_eq!(cpu.registers().rdx(), 6);
+        assert!(cpu.registers().rflags().contains(Flags::CARRY));
+    }
+
+    #[test]
+    fn test_run_budget_and_fault() {
+        // jmp $
+        let mut cpu = cpu_with_program(&[0xeb, 0xfe]);
+        cpu.set_instruction_budget(Some(100));
+        assert_eq!(cpu.run(), StopReason::BudgetExhausted);
+        assert_eq!(cpu.retired_instructions(), 100);
+
+        // idiv rcx with rcx = 0
+        let mut cpu = cpu_with_program(&[0x48, 0xf7, 0xf9]);
+        assert!(matches!(
+            cpu.run(),
+            StopReason::Exception {
+                address: 0x1000,
+                ..
+            }
+        ));
+        assert!(matches!(cpu.exception(), Some(Exception::DivideError)));
+        assert_eq!(cpu.registers().rip(), 0x1000);
+    }
+
+    #[test]
+    fn test_idiv_overflow() {
+        // the most negative dividend divided by -1 for each operand size
+        for (program, rdx, rax) in [
+            (&[0xf6, 0xfb][..], 0, 0x8000),                      // idiv bl
+            (&[0x66, 0xf7, 0xfb][..], 0x8000, 0),                // idiv bx
+            (&[0xf7, 0xfb][..], 0x8000_0000, 0),                 // idiv ebx
+            (&[0x48, 0xf7, 0xfb][..], 0x8000_0000_0000_0000, 0), // idiv rbx
+        ] {
+            let mut cpu = cpu_with_program(program);
+            cpu.registers_mut().write_rdx(rdx);
+            cpu.registers_mut().write_rax(rax);
+            cpu.registers_mut().write_rbx(u64::MAX);
+            assert!(matches!(
+                cpu.step(),
+                Some(StopReason::Exception {
+                    address: 0x1000,
+                    ..
+                })
+            ));
+            assert!(matches!(cpu.exception(), Some(Exception::DivideError)));
+            assert_eq!(cpu.registers().rdx(), rdx);
+            assert_eq!(cpu.registers().rax(), rax);
+        }
+    }
+
+    #[test]
+    fn test_paged_execution() {
+        use crate::paging::PageFaultErrorCode;
+        use cpu::Endianness::LittleEndian;
+
+        // identity map the first 64 KiB with 4 KiB pages, except page 6
+        let mut dram = DRAM::new(0, 1 << 16);
+        dram.alloc(0, 1 << 16).unwrap();
+        for (entry, table) in [(0x1000, 0x2000), (0x2000, 0x3000), (0x3000, 0x4000)] {
+            dram.write_u64(entry, table | 0b11, LittleEndian).unwrap();
+        }
+        for page in (0..16u64).filter(|page| *page != 6) {
+            dram.write_u64(0x4000 + page as usize * 8, page << 12 | 0b11, LittleEndian)
+                .unwrap();
+        }
+        // mov rax, [0x6000] crossing into page 9
+        dram.write_bytes(0x8ffd, &[0x48, 0x8b, 0x04, 0x25, 0x00, 0x60, 0x00, 0x00])
+            .unwrap();
+        // mov rax, 1 crossing into page 6
+        dram.write_bytes(0x5ffc, &[0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00])
+            .unwrap();
+
+        let mut cpu = Cpu::new(PagingMode::Long);
+        cpu.add_device(Box::new(dram)).unwrap();
+        cpu.mmu_mut().write_cr3(0x1000);
+        cpu.mmu_mut().set_enabled(true);
+
+        cpu.registers_mut().write_rip(0x8ffd);
+        assert!(matches!(
+            cpu.run(),
+            StopReason::Exception {
+                address: 0x8ffd,
+                ..
+            }
+        ));
+        assert!(matches!(
+            cpu.exception(),
+            Some(Exception::PageFault { address: 0x6000, error_code }) if error_code.is_empty()
+        ));
+        assert_eq!(cpu.mmu().cr2(), 0x6000);
+
+        cpu.registers_mut().write_rip(0x5ffc);
+        assert!(matches!(
+            cpu.run(),
+            StopReason::Exception {
+                address: 0x5ffc,
+                ..
+            }
+        ));
+        assert!(matches!(
+            cpu.exception(),
+            Some(Exception::PageFault { address: 0x6000, error_code })
+                if *error_code == PageFaultErrorCode::INSTRUCTION_FETCH
+        ));
+
+        // an instruction ending at the page boundary does not touch page 6
+        cpu.bus_mut().write_byte(0x5fff, 0xf4).unwrap();
+        cpu.registers_mut().write_rip(0x5fff);
+        assert_eq!(cpu.run(), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        use cpu::timer::{Timer, CONTROL_ENABLE, CONTROL_INTERRUPT, CONTROL_PERIODIC};
+        use cpu::{Addressable, Endianness::LittleEndian};
+
+        // jmp $
+        let mut cpu = cpu_with_program(&[0xeb, 0xfe]);
+        cpu.add_device(Box::new(Timer::new(0x20000, 3))).unwrap();
+        let bus = cpu.bus_mut();
+        bus.write_u64(0x20010, 5, LittleEndian).unwrap();
+        let control = CONTROL_ENABLE | CONTROL_PERIODIC | CONTROL_INTERRUPT;
+        bus.write_u64(0x20000, control, LittleEndian).unwrap();
+
+        assert_eq!(cpu.run(), StopReason::Interrupt { line: 3 });
+        assert_eq!(cpu.retired_instructions(), 5);
+
+        // the line stays asserted until the status is cleared
+        cpu.set_instruction_budget(Some(20));
+        assert_eq!(cpu.run(), StopReason::BudgetExhausted);
+        cpu.bus_mut().write_byte(0x20018, 1).unwrap();
+        assert_eq!(cpu.run(), StopReason::Interrupt { line: 3 });
+        assert_eq!(cpu.retired_instructions(), 30);
+    }
+
+    #[test]
+    fn test_interrupt_wakes_halted_cpu() {
+        use cpu::timer::{Timer, CONTROL_ENABLE, CONTROL_INTERRUPT};
+        use cpu::{Addressable, Endianness::LittleEndian};
+
+        // hlt; hlt
+        let mut cpu = cpu_with_program(&[0xf4, 0xf4]);
+        for (base, line, comparator) in [(0x20000, 5, 4), (0x20020, 3, 4), (0x20040, 6, 1)] {
+            cpu.add_device(Box::new(Timer::new(base, line))).unwrap();
+            let bus = cpu.bus_mut();
+            bus.write_u64(base + 0x10, comparator, LittleEndian)
+                .unwrap();
+            bus.write_u64(base, CONTROL_ENABLE | CONTROL_INTERRUPT, LittleEndian)
+                .unwrap();
+        }
+
+        // the line raised on the HLT cycle wakes the CPU right away
+        assert_eq!(cpu.step(), Some(StopReason::Interrupt { line: 6 }));
+        assert!(!cpu.halted());
+        assert_eq!(cpu.step(), Some(StopReason::Halted));
+
+        // the devices keep ticking while halted, both lines raised together are reported
+        assert_eq!(cpu.step(), Some(StopReason::Halted));
+        assert_eq!(cpu.step(), Some(StopReason::Interrupt { line: 3 }));
+        assert_eq!(cpu.step(), Some(StopReason::Interrupt { line: 5 }));
+        assert!(!cpu.halted());
+        assert_eq!(cpu.retired_instructions(), 2);
+        assert_eq!(cpu.registers().rip(), 0x1002);
+    }
+
+    #[test]
+    fn test_step_and_run_until() {
+        let mut cpu = cpu_with_program(&[
+            0x48, 0xff, 0xc0, // inc rax
+            0xeb, 0xfb, // jmp $-3
+        ]);
+        assert_eq!(cpu.step(), None);
+        assert_eq!(cpu.registers().rax(), 1);
+        assert_eq!(cpu.registers().rip(), 0x1003);
+
+        assert_eq!(cpu.run_for(3), StopReason::BudgetExhausted);
+        assert_eq!(cpu.registers().rax(), 2);
+
+        let reason = cpu.run_until(|cpu| cpu.registers().rax() == 10);
+        assert_eq!(reason, StopReason::Breakpoint { address: 0x1003 });
+        assert_eq!(cpu.retired_instructions(), 19);
+    }
+
+    #[test]
+    fn test_memory_addressing() {
+        let mut cpu = cpu_with_program(&[
+            0x48, 0x8b, 0x05, 0x0e, 0x00, 0x00, 0x00, // mov rax, [rip + 0xe]
+            0x64, 0x48, 0x8b, 0x1c, 0x25, 0x08, 0x00, 0x00, 0x00, // mov rbx, fs:[8]
+            0x48, 0x8b, 0x4d, 0xf8, // mov rcx, [rbp - 8]
+            0xf4, // hlt
+            0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00, // dq 0xdeadbeef
+        ]);
+        cpu.registers_mut().write_fs_base(0x8000);
+        cpu.registers_mut().write_rbp(0x8100);
+        cpu.write_memory(0x8008, OperandSize::Qword, 0x1234)
+            .unwrap();
+        cpu.write_memory(0x80f8, OperandSize::Qword, 0x5678)
+            .unwrap();
+
+        assert_eq!(cpu.run(), StopReason::Halted);
+        assert_eq!(cpu.registers().rax(), 0xdeadbeef);
+        assert_eq!(cpu.registers().rbx(), 0x1234);
+        assert_eq!(cpu.registers().rcx(), 0x5678);
+    }
+
+    fn boot_sector(program: &[u8]) -> Cpu {
+        let mut dram = DRAM::new(0, 1 << 21);
+        dram.alloc(0, 0x20000).unwrap();
+        dram.alloc(0x100000, 0x1000).unwrap();
+        dram.write_bytes(0x7c00, program).unwrap();
+        dram.write_bytes(0x10204, &[0xef, 0xbe]).unwrap();
+        dram.write_bytes(0, &[0x34, 0x12]).unwrap();
+        dram.write_bytes(0x100000, &[0x78, 0x56]).unwrap();
+
+        let mut cpu = Cpu::new(PagingMode::Real);
+        cpu.add_device(Box::new(dram)).unwrap();
+        cpu.registers_mut().write_segment(Segment::Cs, 0x07c0);
+        cpu
+    }
+
+    #[test]
+    fn test_real_mode() {
+        let program = [
+            0xb8, 0x00, 0x10, // mov ax, 0x1000
+            0x8e, 0xd8, // mov ds, ax
+            0x8e, 0xd0, // mov ss, ax
+            0x31, 0xe4, // xor sp, sp
+            0xbb, 0x00, 0x02, // mov bx, 0x200
+            0xbe, 0x04, 0x00, // mov si, 4
+            0x8b, 0x00, // mov ax, [bx + si]
+            0x50, // push ax
+            0xb9, 0xff, 0xff, // mov cx, 0xffff
+            0x8e, 0xc1, // mov es, cx
+            0x26, 0x8b, 0x16, 0x10, 0x00, // mov dx, es:[0x10]
+            0xe8, 0x01, 0x00, // call inc
+            0xf4, // hlt
+            0x41, // inc: inc cx
+            0xc3, // ret
+        ];
+        let mut cpu = boot_sector(&program);
+        assert!(!cpu.mmu().a20());
+
+        assert_eq!(cpu.run(), StopReason::Halted);
+        assert_eq!(cpu.registers().rip(), 0x20);
+        assert_eq!(cpu.registers().ax(), 0xbeef);
+        assert_eq!(cpu.registers().segment(Segment::Ds), 0x1000);
+        // the stack pointer wraps around within the segment
+        assert_eq!(cpu.registers().rsp(), 0xfffe);
+        assert_eq!(cpu.read_memory(0x1fffe, OperandSize::Word).unwrap(), 0xbeef);
+        assert_eq!(cpu.registers().rcx(), 0);
+        assert!(cpu.registers().rflags().contains(Flags::ZERO));
+        // FFFF:0010 wraps around to 0 with the A20 gate disabled
+        assert_eq!(cpu.registers().dx(), 0x1234);
+
+        let mut cpu = boot_sector(&program);
+        cpu.mmu_mut().set_a20(true);
+        assert_eq!(cpu.run(), StopReason::Halted);
+        assert_eq!(cpu.registers().dx(), 0x5678);
+    }
+}
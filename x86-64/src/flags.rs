@@ -0,0 +1,277 @@
+//! RFLAGS computation for arithmetic and logic instructions
+//!
+//! Every operation returns its truncated result together with a [`FlagUpdate`]
+//! describing which status flags it defines and which ones the Intel SDM
+//! leaves undefined. The update is applied to RFLAGS according to an
+//! [`UndefinedFlags`] policy.
+
+use crate::instruction::OperandSize;
+use crate::register::Flags;
+
+/// The status flags affected by arithmetic instructions
+pub const STATUS_FLAGS: Flags = Flags::CARRY
+    .union(Flags::PARITY)
+    .union(Flags::ADJUST)
+    .union(Flags::ZERO)
+    .union(Flags::SIGN)
+    .union(Flags::OVERFLOW);
+
+/// How flags left undefined by the Intel SDM are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UndefinedFlags {
+    /// Undefined flags keep their previous value
+    #[default]
+    Preserve,
+    /// Undefined flags are cleared
+    Clear,
+    /// SF, ZF and PF reflect the result, other undefined flags are cleared
+    FromResult,
+}
+
+/// The effect of an instruction on the status flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlagUpdate {
+    /// The flags defined by the instruction
+    defined: Flags,
+    /// The flags left undefined by the instruction
+    undefined: Flags,
+    /// The values of the defined flags
+    values: Flags,
+    /// SF, ZF and PF computed from the result
+    result: Flags,
+}
+
+impl FlagUpdate {
+    /// Returns the flags written by the instruction
+    pub fn defined(&self) -> Flags {
+        self.defined
+    }
+
+    /// Returns the flags the instruction leaves undefined
+    pub fn undefined(&self) -> Flags {
+        self.undefined
+    }
+
+    /// Applies the update to `flags`
+    pub fn apply(&self, flags: &mut Flags, policy: UndefinedFlags) {
+        flags.remove(self.defined);
+        flags.insert(self.values & self.defined);
+
+        match policy {
+            UndefinedFlags::Preserve => {}
+            UndefinedFlags::Clear => flags.remove(self.undefined),
+            UndefinedFlags::FromResult => {
+                flags.remove(self.undefined);
+                flags.insert(self.result & self.undefined);
+            }
+        }
+    }
+}
+
+/// Computes SF, ZF and PF of a truncated result
+fn result_flags(result: u64, size: OperandSize) -> Flags {
+    let mut flags = Flags::empty();
+    flags.set(Flags::SIGN, result & size.sign_bit() != 0);
+    flags.set(Flags::ZERO, result == 0);
+    flags.set(Flags::PARITY, (result as u8).count_ones().is_multiple_of(2));
+    flags
+}
+
+fn update(defined: Flags, undefined: Flags, values: Flags, result: Flags) -> FlagUpdate {
+    FlagUpdate {
+        defined,
+        undefined,
+        values,
+        result,
+    }
+}
+
+/// ADD: all status flags are defined
+pub fn add(a: u64, b: u64, size: OperandSize) -> (u64, FlagUpdate) {
+    let (a, b) = (a & size.mask(), b & size.mask());
+    let result = a.wrapping_add(b) & size.mask();
+    let flags = result_flags(result, size);
+    let mut values = flags;
+    values.set(Flags::CARRY, result < a);
+    values.set(Flags::ADJUST, (a ^ b ^ result) & 0x10 != 0);
+    values.set(
+        Flags::OVERFLOW,
+        !(a ^ b) & (a ^ result) & size.sign_bit() != 0,
+    );
+    (result, update(STATUS_FLAGS, Flags::empty(), values, flags))
+}
+
+/// SUB and CMP: all status flags are defined
+pub fn sub(a: u64, b: u64, size: OperandSize) -> (u64, FlagUpdate) {
+    let (a, b) = (a & size.mask(), b & size.mask());
+    let result = a.wrapping_sub(b) & size.mask();
+    let flags = result_flags(result, size);
+    let mut values = flags;
+    values.set(Flags::CARRY, a < b);
+    values.set(Flags::ADJUST, (a ^ b ^ result) & 0x10 != 0);
+    values.set(
+        Flags::OVERFLOW,
+        (a ^ b) & (a ^ result) & size.sign_bit() != 0,
+    );
+    (result, update(STATUS_FLAGS, Flags::empty(), values, flags))
+}
+
+/// INC: like ADD with 1, but CF is not affected
+pub fn inc(a: u64, size: OperandSize) -> (u64, FlagUpdate) {
+    let (result, mut update) = add(a, 1, size);
+    update.defined.remove(Flags::CARRY);
+    (result, update)
+}
+
+/// DEC: like SUB with 1, but CF is not affected
+pub fn dec(a: u64, size: OperandSize) -> (u64, FlagUpdate) {
+    let (result, mut update) = sub(a, 1, size);
+    update.defined.remove(Flags::CARRY);
+    (result, update)
+}
+
+/// NEG: like SUB from 0, CF is set unless the operand is 0
+pub fn neg(a: u64, size: OperandSize) -> (u64, FlagUpdate) {
+    let (result, mut update) = sub(0, a, size);
+    update.values.set(Flags::CARRY, a & size.mask() != 0);
+    (result, update)
+}
+
+/// AND, OR, XOR and TEST: CF and OF are cleared, AF is undefined
+pub fn logic(result: u64, size: OperandSize) -> (u64, FlagUpdate) {
+    let result = result & size.mask();
+    let flags = result_flags(result, size);
+    (
+        result,
+        update(STATUS_FLAGS - Flags::ADJUST, Flags::ADJUST, flags, flags),
+    )
+}
+
+/// Two- and three-operand IMUL: CF and OF are set when the signed result is
+/// truncated, SF, ZF, AF and PF are undefined
+pub fn imul(a: u64, b: u64, size: OperandSize) -> (u64, FlagUpdate) {
+    let product = size.sign_extend(a) as i128 * size.sign_extend(b) as i128;
+    let result = product as u64 & size.mask();
+    let overflow = size.sign_extend(result) as i128 != product;
+
+    let mut values = Flags::empty();
+    values.set(Flags::CARRY | Flags::OVERFLOW, overflow);
+    (
+        result,
+        update(
+            Flags::CARRY | Flags::OVERFLOW,
+            Flags::SIGN | Flags::ZERO | Flags::ADJUST | Flags::PARITY,
+            values,
+            result_flags(result, size),
+        ),
+    )
+}
+
+/// IDIV: all status flags are undefined
+pub fn idiv(quotient: u64, size: OperandSize) -> FlagUpdate {
+    let quotient = quotient & size.mask();
+    update(
+        Flags::empty(),
+        STATUS_FLAGS,
+        Flags::empty(),
+        result_flags(quotient, size),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use OperandSize::*;
+
+    fn flags_of(update: FlagUpdate) -> Flags {
+        let mut flags = Flags::empty();
+        update.apply(&mut flags, UndefinedFlags::Clear);
+        flags
+    }
+
+    #[test]
+    fn test_add_sub_per_size() {
+        let (result, update) = add(0x7f, 1, Byte);
+        assert_eq!(result, 0x80);
+        assert_eq!(
+            flags_of(update),
+            Flags::SIGN | Flags::OVERFLOW | Flags::ADJUST
+        );
+
+        let (result, update) = add(0xffff, 1, Word);
+        assert_eq!(result, 0);
+        assert_eq!(
+            flags_of(update),
+            Flags::CARRY | Flags::ZERO | Flags::PARITY | Flags::ADJUST
+        );
+
+        // the upper bits do not take part in a 32-bit addition
+        let (result, update) = add(0x1_ffff_ffff, 1, Dword);
+        assert_eq!(result, 0);
+        assert!(flags_of(update).contains(Flags::CARRY | Flags::ZERO));
+
+        let (result, update) = sub(0, 1, Qword);
+        assert_eq!(result, u64::MAX);
+        assert_eq!(
+            flags_of(update),
+            Flags::CARRY | Flags::SIGN | Flags::PARITY | Flags::ADJUST
+        );
+
+        let (_, update) = sub(0x8000_0000, 1, Dword);
+        assert_eq!(
+            flags_of(update),
+            Flags::OVERFLOW | Flags::ADJUST | Flags::PARITY
+        );
+    }
+
+    #[test]
+    fn test_inc_dec_neg_preserve_carry() {
+        let mut flags = Flags::CARRY;
+        inc(0xff, Byte)
+            .1
+            .apply(&mut flags, UndefinedFlags::Preserve);
+        assert_eq!(
+            flags,
+            Flags::CARRY | Flags::ZERO | Flags::PARITY | Flags::ADJUST
+        );
+
+        let mut flags = Flags::empty();
+        dec(0x8000, Word)
+            .1
+            .apply(&mut flags, UndefinedFlags::Preserve);
+        assert_eq!(flags, Flags::OVERFLOW | Flags::ADJUST | Flags::PARITY);
+
+        assert_eq!(flags_of(neg(0, Qword).1), Flags::ZERO | Flags::PARITY);
+        assert!(flags_of(neg(5, Dword).1).contains(Flags::CARRY | Flags::SIGN));
+    }
+
+    #[test]
+    fn test_undefined_flag_policy() {
+        let (_, update) = logic(0x80, Byte);
+        assert_eq!(update.undefined(), Flags::ADJUST);
+
+        let mut flags = Flags::ADJUST | Flags::CARRY;
+        update.apply(&mut flags, UndefinedFlags::Preserve);
+        assert_eq!(flags, Flags::ADJUST | Flags::SIGN);
+
+        let (result, update) = imul(0x4000_0000, 4, Dword);
+        assert_eq!(result, 0);
+        let mut flags = Flags::SIGN;
+        update.apply(&mut flags, UndefinedFlags::Preserve);
+        assert_eq!(flags, Flags::SIGN | Flags::CARRY | Flags::OVERFLOW);
+        let mut flags = Flags::SIGN;
+        update.apply(&mut flags, UndefinedFlags::FromResult);
+        assert_eq!(
+            flags,
+            Flags::CARRY | Flags::OVERFLOW | Flags::ZERO | Flags::PARITY
+        );
+
+        let (result, update) = imul(-3i64 as u64, 7, Word);
+        assert_eq!(result, (-21i16) as u16 as u64);
+        assert_eq!(flags_of(update), Flags::empty());
+
+        let mut flags = STATUS_FLAGS;
+        idiv(1, Qword).apply(&mut flags, UndefinedFlags::Clear);
+        assert_eq!(flags, Flags::empty());
+    }
+}
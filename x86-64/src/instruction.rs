@@ -1,35 +1,311 @@
-#[derive(Debug, Clone, Copy)]
+use crate::register::Flags;
+
+/// The width of an operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OperandSize {
+    Byte,
+    Word,
+    Dword,
+    Qword,
+}
+
+impl OperandSize {
+    /// Returns the size in bytes
+    pub fn bytes(self) -> usize {
+        match self {
+            OperandSize::Byte => 1,
+            OperandSize::Word => 2,
+            OperandSize::Dword => 4,
+            OperandSize::Qword => 8,
+        }
+    }
+
+    /// Returns the size in bits
+    pub fn bits(self) -> u32 {
+        self.bytes() as u32 * 8
+    }
+
+    /// Returns a mask covering all bits of the operand
+    pub fn mask(self) -> u64 {
+        u64::MAX >> (64 - self.bits())
+    }
+
+    /// Returns a mask with only the sign bit of the operand set
+    pub fn sign_bit(self) -> u64 {
+        1 << (self.bits() - 1)
+    }
+
+    /// Sign-extends the low bits of `value` to 64 bits
+    pub fn sign_extend(self, value: u64) -> i64 {
+        let shift = 64 - self.bits();
+        ((value << shift) as i64) >> shift
+    }
+}
+
+/// The condition codes tested by Jcc, SETcc and CMOVcc
+///
+/// The discriminant is the 4-bit condition encoded in the low nibble of the opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Condition {
+    /// Overflow (OF = 1)
+    O = 0x0,
+    /// Not overflow (OF = 0)
+    No = 0x1,
+    /// Below, carry (CF = 1)
+    B = 0x2,
+    /// Above or equal, not carry (CF = 0)
+    Ae = 0x3,
+    /// Equal, zero (ZF = 1)
+    E = 0x4,
+    /// Not equal, not zero (ZF = 0)
+    Ne = 0x5,
+    /// Below or equal (CF = 1 or ZF = 1)
+    Be = 0x6,
+    /// Above (CF = 0 and ZF = 0)
+    A = 0x7,
+    /// Sign (SF = 1)
+    S = 0x8,
+    /// Not sign (SF = 0)
+    Ns = 0x9,
+    /// Parity even (PF = 1)
+    P = 0xa,
+    /// Parity odd (PF = 0)
+    Np = 0xb,
+    /// Less (SF != OF)
+    L = 0xc,
+    /// Greater or equal (SF = OF)
+    Ge = 0xd,
+    /// Less or equal (ZF = 1 or SF != OF)
+    Le = 0xe,
+    /// Greater (ZF = 0 and SF = OF)
+    G = 0xf,
+}
+
+impl Condition {
+    /// Returns the condition encoded by the low nibble of `code`
+    pub fn from_code(code: u8) -> Self {
+        use Condition::*;
+        [O, No, B, Ae, E, Ne, Be, A, S, Ns, P, Np, L, Ge, Le, G][(code & 0xf) as usize]
+    }
+
+    /// Returns the 4-bit encoding of the condition
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the condition that holds exactly when `self` does not
+    pub fn negate(self) -> Self {
+        Self::from_code(self.code() ^ 1)
+    }
+
+    /// Evaluates the condition against the status flags
+    pub fn evaluate(self, flags: Flags) -> bool {
+        let cf = flags.contains(Flags::CARRY);
+        let zf = flags.contains(Flags::ZERO);
+        let sf = flags.contains(Flags::SIGN);
+        let of = flags.contains(Flags::OVERFLOW);
+        let pf = flags.contains(Flags::PARITY);
+
+        let result = match self.code() >> 1 {
+            0 => of,
+            1 => cf,
+            2 => zf,
+            3 => cf || zf,
+            4 => sf,
+            5 => pf,
+            6 => sf != of,
+            _ => zf || sf != of,
+        };
+        // odd encodings negate the even ones
+        result != (self.code() & 1 != 0)
+    }
+}
+
+/// A register operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reg {
+    /// The low bits of the general purpose register with the given encoding index
+    Gpr(u8, OperandSize),
+    /// AH, CH, DH or BH, bits 8..16 of the register with the given index (0 to 3)
+    HighByte(u8),
+    /// The selector of a segment register, only used by MOV
+    Segment(Segment),
+}
+
+impl Reg {
+    /// Returns the encoding index of the register holding the operand
+    pub fn index(self) -> u8 {
+        match self {
+            Reg::Gpr(index, _) | Reg::HighByte(index) => index,
+            Reg::Segment(segment) => segment as u8,
+        }
+    }
+
+    /// Returns the width of the operand
+    pub fn size(self) -> OperandSize {
+        match self {
+            Reg::Gpr(_, size) => size,
+            Reg::HighByte(_) => OperandSize::Byte,
+            Reg::Segment(_) => OperandSize::Word,
+        }
+    }
+}
+
+/// A segment register selectable by a segment override prefix
+///
+/// The discriminant is the encoding of the register in the ModRM reg field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Es,
+    Cs,
+    Ss,
+    Ds,
+    Fs,
+    Gs,
+}
+
+/// A memory operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Memory {
+    pub addressing: Addressing,
+    /// The width of the value accessed in memory
+    pub size: OperandSize,
+    /// The segment override, if any
+    pub segment: Option<Segment>,
+    /// The width of the registers and the wraparound of the effective address
+    pub address_size: OperandSize,
+}
+
+impl Memory {
+    pub fn new(addressing: Addressing, size: OperandSize) -> Self {
+        Self {
+            addressing,
+            size,
+            segment: None,
+            address_size: OperandSize::Qword,
+        }
+    }
+
+    /// Returns the operand with the given segment override
+    pub fn with_segment(self, segment: Option<Segment>) -> Self {
+        Self { segment, ..self }
+    }
+
+    /// Returns the operand with the given address size
+    pub fn with_address_size(self, address_size: OperandSize) -> Self {
+        Self {
+            address_size,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Dest {
-    Reg(u8),
-    Mem(Addressing),
+    Reg(Reg),
+    Mem(Memory),
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Dest {
+    /// Returns the width of the operand
+    pub fn size(&self) -> OperandSize {
+        match self {
+            Dest::Reg(reg) => reg.size(),
+            Dest::Mem(mem) => mem.size,
+        }
+    }
+}
+
+/// A source operand
+///
+/// Immediates are stored sign-extended and take the size of the instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Src {
-    Reg(u8),
-    Mem(Addressing),
+    Reg(Reg),
+    Mem(Memory),
     Imm(u64),
 }
 
-#[derive(Debug, Clone, Copy)]
+impl From<Dest> for Src {
+    fn from(dest: Dest) -> Self {
+        match dest {
+            Dest::Reg(reg) => Src::Reg(reg),
+            Dest::Mem(mem) => Src::Mem(mem),
+        }
+    }
+}
+
+impl Src {
+    /// Returns the width of the operand, or `None` for immediates
+    pub fn size(&self) -> Option<OperandSize> {
+        match self {
+            Src::Reg(reg) => Some(reg.size()),
+            Src::Mem(mem) => Some(mem.size),
+            Src::Imm(_) => None,
+        }
+    }
+}
+
+/// The effective address computation of a memory operand
+///
+/// Displacements are signed. `Displacement` is an absolute address, while
+/// `RipRelative` is relative to the end of the instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Addressing {
-    Displacement(u64),
+    Displacement(i64),
+    /// RIP + Displacement
+    RipRelative(i64),
     Base(u8),
     /// Base + Index
     BaseIndex(u8, u8),
     /// Base + Displacement
-    BaseDisplacement(u8, u64),
+    BaseDisplacement(u8, i64),
     /// Base + Index + Displacement
-    BaseIndexDisplacement(u8, u8, u64),
+    BaseIndexDisplacement(u8, u8, i64),
     /// Base + Index * Scale
     BaseIndexScale(u8, u8, u8),
     /// Index * Scale + Displacement
-    IndexScaleDisplacement(u8, u8, u64),
+    IndexScaleDisplacement(u8, u8, i64),
     /// Base + Index * Scale + Displacement
-    BaseIndexScaleDisplacement(u8, u8, u8, u64),
+    BaseIndexScaleDisplacement(u8, u8, u8, i64),
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Addressing {
+    /// Builds the most specific addressing form for the given components
+    ///
+    /// `index` is a pair of index register and scale (1, 2, 4 or 8).
+    pub fn new(base: Option<u8>, index: Option<(u8, u8)>, displacement: i64) -> Self {
+        match (base, index, displacement) {
+            (None, None, d) => Addressing::Displacement(d),
+            (Some(b), None, 0) => Addressing::Base(b),
+            (Some(b), None, d) => Addressing::BaseDisplacement(b, d),
+            (Some(b), Some((i, 1)), 0) => Addressing::BaseIndex(b, i),
+            (Some(b), Some((i, s)), 0) => Addressing::BaseIndexScale(b, i, s),
+            (Some(b), Some((i, 1)), d) => Addressing::BaseIndexDisplacement(b, i, d),
+            (Some(b), Some((i, s)), d) => Addressing::BaseIndexScaleDisplacement(b, i, s, d),
+            (None, Some((i, s)), d) => Addressing::IndexScaleDisplacement(i, s, d),
+        }
+    }
+
+    /// Returns the base register, the index register with its scale and the displacement
+    ///
+    /// RIP-relative addressing reports neither base nor index, callers have to
+    /// add the address of the next instruction themselves.
+    pub fn components(&self) -> (Option<u8>, Option<(u8, u8)>, i64) {
+        match *self {
+            Addressing::Displacement(d) | Addressing::RipRelative(d) => (None, None, d),
+            Addressing::Base(b) => (Some(b), None, 0),
+            Addressing::BaseIndex(b, i) => (Some(b), Some((i, 1)), 0),
+            Addressing::BaseDisplacement(b, d) => (Some(b), None, d),
+            Addressing::BaseIndexDisplacement(b, i, d) => (Some(b), Some((i, 1)), d),
+            Addressing::BaseIndexScale(b, i, s) => (Some(b), Some((i, s)), 0),
+            Addressing::IndexScaleDisplacement(i, s, d) => (None, Some((i, s)), d),
+            Addressing::BaseIndexScaleDisplacement(b, i, s, d) => (Some(b), Some((i, s)), d),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instr {
     Mov(Dest, Src),
     Push(Src),
@@ -50,14 +326,83 @@ pub enum Instr {
 
     Cmp(Dest, Src),
     Test(Dest, Src),
+    Setcc(Condition, Dest),
+    Cmovcc(Condition, Dest, Src),
+
     Jmp(Src),
-    Je(Src),
-    Jz(Src),
-    Jnz(Src),
-    Jg(Src),
-    Jge(Src),
-    Jl(Src),
-    Jle(Src),
+    Jcc(Condition, Src),
     Call(Src),
     Ret,
+
+    Nop,
+    Hlt,
+}
+
+impl Instr {
+    /// Returns the operand size of the instruction
+    ///
+    /// Branches and instructions without operands use the 64-bit operand size.
+    pub fn operand_size(&self) -> OperandSize {
+        match self {
+            Instr::Mov(dest, _)
+            | Instr::Pop(dest)
+            | Instr::Add(dest, _)
+            | Instr::Sub(dest, _)
+            | Instr::Inc(dest)
+            | Instr::Dec(dest)
+            | Instr::IMul(dest, _)
+            | Instr::And(dest, _)
+            | Instr::Or(dest, _)
+            | Instr::Xor(dest, _)
+            | Instr::Not(dest)
+            | Instr::Neg(dest)
+            | Instr::Cmp(dest, _)
+            | Instr::Test(dest, _)
+            | Instr::Setcc(_, dest)
+            | Instr::Cmovcc(_, dest, _) => dest.size(),
+            Instr::Push(src) | Instr::IDiv(src) => src.size().unwrap_or(OperandSize::Qword),
+            Instr::Jmp(_)
+            | Instr::Jcc(_, _)
+            | Instr::Call(_)
+            | Instr::Ret
+            | Instr::Nop
+            | Instr::Hlt => OperandSize::Qword,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_condition() {
+        for code in 0..16 {
+            let condition = Condition::from_code(code);
+            assert_eq!(condition.code(), code);
+            for bits in 0..32u64 {
+                let flags = Flags::from_bits_truncate(
+                    (bits & 1)
+                        | (bits & 2) << 1
+                        | (bits & 4) << 4
+                        | (bits & 8) << 4
+                        | (bits & 16) << 7,
+                );
+                assert_ne!(
+                    condition.evaluate(flags),
+                    condition.negate().evaluate(flags)
+                );
+            }
+        }
+
+        let below = Flags::CARRY | Flags::SIGN;
+        assert!(Condition::B.evaluate(below));
+        assert!(Condition::Be.evaluate(below));
+        assert!(!Condition::A.evaluate(below));
+        assert!(Condition::L.evaluate(below));
+        assert!(!Condition::G.evaluate(Flags::ZERO));
+        assert!(Condition::Ge.evaluate(Flags::SIGN | Flags::OVERFLOW));
+        assert!(Condition::P.evaluate(Flags::PARITY));
+        assert!(Condition::No.evaluate(Flags::empty()));
+    }
 }
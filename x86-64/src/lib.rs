@@ -1,6 +1,15 @@
-use cpu::CpuFeatures;
-use paging::{MMU, PagingMode};
+use cpu::bus::Bus;
+use cpu::{CpuFeatures, InterruptLines, MemoryAccessError, PrivilegeLevel, StopReason, Tick};
+use execute::{Exception, Flow};
+use flags::UndefinedFlags;
+use paging::{PagingMode, MMU};
 
+pub mod assembler;
+pub mod decoder;
+pub mod disassembler;
+pub mod encoder;
+pub mod execute;
+pub mod flags;
 pub mod instruction;
 pub mod paging;
 pub mod register;
@@ -9,12 +18,176 @@ pub mod simd;
 pub struct Cpu {
     mmu: MMU,
     registers: register::Registers,
-    device: Vec<Box<dyn cpu::Device>>,
+    bus: Bus,
+    /// Maximum number of instructions executed by a single call to `run`
+    instruction_budget: Option<u64>,
+    /// Number of instructions retired since the CPU was created
+    retired: u64,
+    /// How flags left undefined by an instruction are written
+    undefined_flags: UndefinedFlags,
+    halted: bool,
+    /// Interrupt lines raised by the devices that have not been reported yet
+    pending: InterruptLines,
+    exception: Option<Exception>,
+    /// The privilege level memory accesses are checked against
+    privilege: PrivilegeLevel,
+}
+
+impl Cpu {
+    pub fn new(paging_mode: PagingMode) -> Self {
+        Self {
+            mmu: MMU::new(paging_mode),
+            registers: register::Registers::new(),
+            bus: Bus::new(),
+            instruction_budget: None,
+            retired: 0,
+            undefined_flags: UndefinedFlags::default(),
+            halted: false,
+            pending: InterruptLines::new(),
+            exception: None,
+            privilege: PrivilegeLevel::Supervisor,
+        }
+    }
+
+    /// Limits the number of instructions executed by each call to `run`
+    pub fn set_instruction_budget(&mut self, budget: Option<u64>) {
+        self.instruction_budget = budget;
+    }
+
+    /// Selects how flags left undefined by the Intel SDM are written
+    pub fn set_undefined_flags(&mut self, policy: UndefinedFlags) {
+        self.undefined_flags = policy;
+    }
+
+    /// Returns the number of instructions retired so far
+    pub fn retired_instructions(&self) -> u64 {
+        self.retired
+    }
+
+    /// Returns if the CPU is halted
+    ///
+    /// A halted CPU does not execute any further instructions until a device
+    /// raises an interrupt line.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Returns the fault raised by the last executed instruction, if any
+    pub fn exception(&self) -> Option<&Exception> {
+        self.exception.as_ref()
+    }
+
+    pub fn registers(&self) -> &register::Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut register::Registers {
+        &mut self.registers
+    }
+
+    /// Counts a retired instruction and ticks the devices
+    ///
+    /// Every instruction takes a single cycle.
+    fn retire(&mut self) {
+        self.retired += 1;
+        self.tick(Tick {
+            cycles: 1,
+            instructions: 1,
+        });
+    }
+
+    /// Ticks the devices and records the newly raised lines as pending
+    fn tick(&mut self, tick: Tick) {
+        let raised = self.bus.tick(tick);
+        self.pending = self.pending.union(&raised);
+    }
+
+    /// Reports the lowest pending interrupt line, waking the CPU up
+    fn take_interrupt(&mut self) -> Option<StopReason> {
+        let line = self.pending.raised().next()?;
+        self.pending.lower(line);
+        self.halted = false;
+        Some(StopReason::Interrupt { line })
+    }
+
+    /// Returns the privilege level memory accesses are checked against
+    pub fn privilege_level(&self) -> PrivilegeLevel {
+        self.privilege
+    }
+
+    pub fn set_privilege_level(&mut self, privilege: PrivilegeLevel) {
+        self.privilege = privilege;
+    }
+
+    /// Returns the memory management unit translating linear addresses
+    pub fn mmu(&self) -> &MMU {
+        &self.mmu
+    }
+
+    pub fn mmu_mut(&mut self) -> &mut MMU {
+        &mut self.mmu
+    }
+
+    /// Returns the system bus the devices are attached to
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
 }
 
 impl cpu::Cpu for Cpu {
-    fn run(&mut self) {
-        todo!()
+    fn step(&mut self) -> Option<StopReason> {
+        // lines raised together are reported one by one before executing on
+        if let Some(reason) = self.take_interrupt() {
+            return Some(reason);
+        }
+        if self.halted {
+            // the devices keep running while the CPU waits for an interrupt
+            self.tick(Tick {
+                cycles: 1,
+                instructions: 0,
+            });
+            return self.take_interrupt().or(Some(StopReason::Halted));
+        }
+
+        self.exception = None;
+        match self.step_instruction() {
+            Ok(Flow::Continue) => {
+                self.retire();
+                self.take_interrupt()
+            }
+            Ok(Flow::Halt) => {
+                self.retire();
+                self.halted = true;
+                self.take_interrupt().or(Some(StopReason::Halted))
+            }
+            Err(exception) => {
+                let reason = StopReason::Exception {
+                    address: self.registers.rip() as usize,
+                    description: exception.to_string(),
+                };
+                self.exception = Some(exception);
+                Some(reason)
+            }
+        }
+    }
+
+    fn run(&mut self) -> StopReason {
+        match self.instruction_budget {
+            Some(budget) => self.run_for(budget),
+            None => loop {
+                if let Some(reason) = self.step() {
+                    return reason;
+                }
+            },
+        }
+    }
+
+    fn program_counter(&self) -> usize {
+        self.registers.rip() as usize
     }
 
     fn general_register_size(&self) -> usize {
@@ -25,8 +198,8 @@ impl cpu::Cpu for Cpu {
         cpu::Endianness::LittleEndian
     }
 
-    fn add_device(&mut self, device: Box<dyn cpu::Device>) {
-        self.device.push(device);
+    fn add_device(&mut self, device: Box<dyn cpu::Device>) -> Result<(), MemoryAccessError> {
+        self.bus.attach(device)
     }
 
     fn features(&self) -> cpu::CpuFeatures {
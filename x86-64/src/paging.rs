@@ -1,3 +1,7 @@
+use bitflags::bitflags;
+use cpu::{AccessType, Addressable, Endianness, MemoryAccessError, PrivilegeLevel};
+use thiserror::Error;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PagingMode {
     Real,
@@ -6,12 +10,679 @@ pub enum PagingMode {
     LongLA57,
 }
 
+bitflags! {
+    /// The flags of a paging structure entry
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageTableFlags : u64 {
+        const PRESENT = 1 << 0;
+        const WRITABLE = 1 << 1;
+        const USER = 1 << 2;
+        const WRITE_THROUGH = 1 << 3;
+        const CACHE_DISABLE = 1 << 4;
+        const ACCESSED = 1 << 5;
+        const DIRTY = 1 << 6;
+        /// The entry maps a 2 MiB or 1 GiB page instead of referencing a table
+        const HUGE_PAGE = 1 << 7;
+        const GLOBAL = 1 << 8;
+        const NO_EXECUTE = 1 << 63;
+    }
+}
+
+bitflags! {
+    /// The error code pushed by a page fault
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFaultErrorCode : u32 {
+        /// The fault was a protection violation, not a non-present page
+        const PROTECTION_VIOLATION = 1 << 0;
+        /// The access was a write
+        const WRITE = 1 << 1;
+        /// The access was made in user mode
+        const USER = 1 << 2;
+        /// A paging structure entry has a reserved bit set
+        const RESERVED_BIT = 1 << 3;
+        /// The access was an instruction fetch
+        const INSTRUCTION_FETCH = 1 << 4;
+    }
+}
+
+bitflags! {
+    /// The bits of CR4 that select the paging structure format
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Cr4 : u64 {
+        /// Page size extensions, enables 4 MiB pages with 32-bit paging
+        const PSE = 1 << 4;
+        /// Physical address extension, selects PAE paging in protected mode
+        const PAE = 1 << 5;
+    }
+}
+
+/// The layout of the paging structures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// Two levels of 1024 32-bit entries
+    Legacy,
+    /// Four page directory pointers followed by two levels of 512 64-bit entries
+    Pae,
+    /// `levels` levels of 512 64-bit entries
+    Long { levels: u32 },
+}
+
+impl Format {
+    fn levels(self) -> u32 {
+        match self {
+            Format::Legacy => 2,
+            Format::Pae => 3,
+            Format::Long { levels } => levels,
+        }
+    }
+
+    fn entry_size(self) -> u64 {
+        match self {
+            Format::Legacy => 4,
+            _ => 8,
+        }
+    }
+
+    /// Returns the position and the width of the table index at `level`
+    fn index(self, level: u32) -> (u32, u32) {
+        match self {
+            Format::Legacy => (12 + 10 * (level - 1), 10),
+            Format::Pae if level == 3 => (30, 2),
+            _ => (12 + 9 * (level - 1), 9),
+        }
+    }
+
+    /// Returns the mask of the physical address stored in an entry
+    fn address_mask(self) -> u64 {
+        match self {
+            Format::Legacy => 0xffff_f000,
+            _ => ADDRESS_MASK,
+        }
+    }
+}
+
+/// The reserved bits of a PAE page directory pointer
+const PAE_DIRECTORY_POINTER_RESERVED: u64 = 1 << 63 | 0b1_1110_0110;
+
+/// The reserved bit of a 4 MiB page, between the high and low address bits
+const LEGACY_LARGE_PAGE_RESERVED: u64 = 1 << 21;
+
+/// The mask of the physical address stored in a paging structure entry
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The size of a page mapped by the last level of the paging structures
+pub const PAGE_SIZE: u64 = 1 << 12;
+
+/// The PAT bit of an entry mapping a huge page, together with the bits below it
+const HUGE_PAGE_PAT: u64 = (1 << 13) - 1;
+
+/// Errors raised while translating a linear address
+#[derive(Debug, Error)]
+pub enum PagingError {
+    /// #PF, the translation is missing or forbids the access
+    #[error("Page fault at {address:#x} ({error_code:?})")]
+    PageFault {
+        address: u64,
+        error_code: PageFaultErrorCode,
+    },
+
+    /// #GP, the address is not canonical
+    #[error("Non-canonical address {address:#x}")]
+    NonCanonical { address: u64 },
+
+    /// A paging structure could not be accessed
+    #[error(transparent)]
+    Memory(#[from] MemoryAccessError),
+}
+
 pub struct MMU {
     paging_mode: PagingMode,
+    /// CR0.PG, translation is disabled until the paging structures are set up
+    enabled: bool,
+    /// CR3, the physical address of the root paging structure
+    cr3: u64,
+    /// CR2, the linear address of the last page fault
+    cr2: u64,
+    /// The paging bits of CR4
+    cr4: Cr4,
+    /// CR0.WP, supervisor writes honour read-only pages
+    write_protect: bool,
+    /// EFER.NXE, the no-execute bit is honoured
+    no_execute: bool,
+    /// The A20 gate, physical address bit 20 is forced to zero while it is disabled
+    a20: bool,
 }
 
 impl MMU {
+    pub fn new(paging_mode: PagingMode) -> Self {
+        Self {
+            paging_mode,
+            enabled: false,
+            cr3: 0,
+            cr2: 0,
+            cr4: Cr4::empty(),
+            write_protect: true,
+            // EFER.NXE is clear at reset
+            no_execute: false,
+            // real mode starts with the 8086 wraparound at 1 MiB
+            a20: paging_mode != PagingMode::Real,
+        }
+    }
+
     pub fn paging_mode(&self) -> PagingMode {
         self.paging_mode
     }
+
+    /// Returns if linear addresses are translated
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Enables or disables translation of linear addresses
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn cr3(&self) -> u64 {
+        self.cr3
+    }
+
+    pub fn write_cr3(&mut self, cr3: u64) {
+        self.cr3 = cr3;
+    }
+
+    pub fn cr2(&self) -> u64 {
+        self.cr2
+    }
+
+    pub fn write_cr2(&mut self, cr2: u64) {
+        self.cr2 = cr2;
+    }
+
+    pub fn cr4(&self) -> Cr4 {
+        self.cr4
+    }
+
+    /// Selects the format of the paging structures in protected mode
+    pub fn write_cr4(&mut self, cr4: Cr4) {
+        self.cr4 = cr4;
+    }
+
+    /// Sets if supervisor writes to read-only pages fault (CR0.WP)
+    pub fn set_write_protect(&mut self, write_protect: bool) {
+        self.write_protect = write_protect;
+    }
+
+    /// Sets if the no-execute bit is honoured (EFER.NXE)
+    ///
+    /// A set no-execute bit is reserved while this is disabled.
+    pub fn set_no_execute(&mut self, no_execute: bool) {
+        self.no_execute = no_execute;
+    }
+
+    /// Returns if physical address bit 20 is passed through
+    pub fn a20(&self) -> bool {
+        self.a20
+    }
+
+    /// Enables or disables the A20 gate
+    pub fn set_a20(&mut self, a20: bool) {
+        self.a20 = a20;
+    }
+
+    /// Clears bit 20 of a physical address while the A20 gate is disabled
+    pub fn apply_a20(&self, address: u64) -> u64 {
+        if self.a20 {
+            address
+        } else {
+            address & !(1 << 20)
+        }
+    }
+
+    /// Translates a linear address to a physical address
+    ///
+    /// Walks the paging structures in `mem` and sets the accessed bits of the
+    /// entries used, and the dirty bit of the page on writes. Addresses are
+    /// passed through unchanged while translation is disabled. The A20 gate
+    /// is applied to the physical address.
+    pub fn translate(
+        &self,
+        mem: &mut dyn Addressable,
+        address: u64,
+        access: AccessType,
+        privilege: PrivilegeLevel,
+    ) -> Result<u64, PagingError> {
+        if !self.enabled {
+            return Ok(self.apply_a20(address));
+        }
+        let format = match self.paging_mode {
+            PagingMode::Real => return Ok(self.apply_a20(address)),
+            PagingMode::Protected if self.cr4.contains(Cr4::PAE) => Format::Pae,
+            PagingMode::Protected => Format::Legacy,
+            PagingMode::Long => Format::Long { levels: 4 },
+            PagingMode::LongLA57 => Format::Long { levels: 5 },
+        };
+        let physical = self.walk(mem, address, format, access, privilege)?;
+        Ok(self.apply_a20(physical))
+    }
+
+    /// Walks the paging structures of `format` rooted at CR3
+    fn walk(
+        &self,
+        mem: &mut dyn Addressable,
+        address: u64,
+        format: Format,
+        access: AccessType,
+        privilege: PrivilegeLevel,
+    ) -> Result<u64, PagingError> {
+        let address = match format {
+            Format::Long { levels } => {
+                let upper = (address as i64) >> (11 + 9 * levels);
+                if upper != 0 && upper != -1 {
+                    return Err(PagingError::NonCanonical { address });
+                }
+                address
+            }
+            // linear addresses are 32 bits wide outside of long mode
+            Format::Legacy | Format::Pae => address as u32 as u64,
+        };
+
+        let user = privilege == PrivilegeLevel::User;
+        let mut error_code = match access {
+            AccessType::Read => PageFaultErrorCode::empty(),
+            AccessType::Write => PageFaultErrorCode::WRITE,
+            AccessType::Execute => PageFaultErrorCode::INSTRUCTION_FETCH,
+        };
+        error_code.set(PageFaultErrorCode::USER, user);
+        let fault = |error_code| PagingError::PageFault {
+            address,
+            error_code,
+        };
+        let reserved = fault(
+            error_code
+                | PageFaultErrorCode::PROTECTION_VIOLATION
+                | PageFaultErrorCode::RESERVED_BIT,
+        );
+
+        let mut table = match format {
+            Format::Legacy => self.cr3 & 0xffff_f000,
+            Format::Pae => self.cr3 & 0xffff_ffe0,
+            Format::Long { .. } => self.cr3 & ADDRESS_MASK,
+        };
+        let entry_size = format.entry_size();
+        let mut entries = Vec::with_capacity(format.levels() as usize);
+        let mut allowed = PageTableFlags::WRITABLE | PageTableFlags::USER;
+        let mut no_execute = false;
+        for level in (1..=format.levels()).rev() {
+            let (shift, index_bits) = format.index(level);
+            let index = (address >> shift) & ((1 << index_bits) - 1);
+            let entry_address = table + index * entry_size;
+            let entry = mem.read_uint(
+                entry_address as usize,
+                entry_size as usize,
+                Endianness::LittleEndian,
+            )?;
+            let flags = PageTableFlags::from_bits_retain(entry);
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return Err(fault(error_code));
+            }
+            if !self.no_execute && flags.contains(PageTableFlags::NO_EXECUTE) {
+                return Err(reserved);
+            }
+
+            // the PAE page directory pointers carry no permissions
+            if format == Format::Pae && level == 3 {
+                if entry & PAE_DIRECTORY_POINTER_RESERVED != 0 {
+                    return Err(reserved);
+                }
+                table = entry & ADDRESS_MASK;
+                continue;
+            }
+
+            let page_size = 1u64 << shift;
+            let huge = level > 1 && flags.contains(PageTableFlags::HUGE_PAGE);
+            let frame = match format {
+                _ if !huge => None,
+                // 4 MiB pages keep bits 39:32 of their address in bits 20:13
+                Format::Legacy if self.cr4.contains(Cr4::PSE) => {
+                    if entry & LEGACY_LARGE_PAGE_RESERVED != 0 {
+                        return Err(reserved);
+                    }
+                    Some(entry & 0xffc0_0000 | (entry >> 13 & 0xff) << 32)
+                }
+                // the page size bit is ignored without page size extensions
+                Format::Legacy => None,
+                // huge pages are limited to 2 MiB and 1 GiB and must be aligned,
+                // bit 12 of their address holds the PAT bit
+                _ => {
+                    if level > 3 || entry & ADDRESS_MASK & (page_size - 1) & !HUGE_PAGE_PAT != 0 {
+                        return Err(reserved);
+                    }
+                    Some(entry & ADDRESS_MASK & !(page_size - 1))
+                }
+            };
+
+            allowed &= flags;
+            no_execute |= flags.contains(PageTableFlags::NO_EXECUTE);
+            entries.push((entry_address, flags));
+            let frame = match frame {
+                Some(frame) => frame,
+                None if level == 1 => entry & format.address_mask(),
+                None => {
+                    table = entry & format.address_mask();
+                    continue;
+                }
+            };
+
+            if !self.permits(allowed, no_execute, access, user) {
+                return Err(fault(error_code | PageFaultErrorCode::PROTECTION_VIOLATION));
+            }
+            self.update_entries(mem, &entries, entry_size as usize, access)?;
+            return Ok(frame | (address & (page_size - 1)));
+        }
+        unreachable!("the last level always maps a page")
+    }
+
+    /// Returns if the combined permissions of all levels allow the access
+    fn permits(
+        &self,
+        allowed: PageTableFlags,
+        no_execute: bool,
+        access: AccessType,
+        user: bool,
+    ) -> bool {
+        if user && !allowed.contains(PageTableFlags::USER) {
+            return false;
+        }
+        let writable = allowed.contains(PageTableFlags::WRITABLE);
+        match access {
+            AccessType::Read => true,
+            AccessType::Write => writable || (!user && !self.write_protect),
+            AccessType::Execute => !(no_execute && self.no_execute),
+        }
+    }
+
+    /// Sets the accessed bits of the walked entries and the dirty bit of the page
+    fn update_entries(
+        &self,
+        mem: &mut dyn Addressable,
+        entries: &[(u64, PageTableFlags)],
+        entry_size: usize,
+        access: AccessType,
+    ) -> Result<(), MemoryAccessError> {
+        for (index, (address, flags)) in entries.iter().enumerate() {
+            let mut updated = *flags | PageTableFlags::ACCESSED;
+            if index == entries.len() - 1 && access == AccessType::Write {
+                updated |= PageTableFlags::DIRTY;
+            }
+            if updated != *flags {
+                mem.write_uint(
+                    *address as usize,
+                    entry_size,
+                    updated.bits(),
+                    Endianness::LittleEndian,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cpu::device::DRAM;
+    use Endianness::LittleEndian;
+
+    const TABLE: u64 = 0b111; // present, writable, user
+
+    fn linear(indices: [u64; 4], offset: u64) -> u64 {
+        indices
+            .iter()
+            .fold(0, |address, index| (address << 9) | index)
+            << 12
+            | offset
+    }
+
+    /// Builds paging structures with PML5 0x5000, PML4 0x1000, PDPT 0x2000,
+    /// PD 0x3000 and PT 0x4000
+    fn tables() -> DRAM {
+        let mut dram = DRAM::new(0, 1 << 16);
+        dram.alloc(0, 1 << 16).unwrap();
+        for (entry, value) in [
+            (0x5000, 0x1000 | TABLE),
+            (0x1008, 0x2000 | TABLE),
+            (0x2010, 0x3000 | TABLE),
+            (0x3018, 0x4000 | TABLE),
+            (0x4020, 0x8000 | TABLE),
+            (0x4028, 0x9000 | 0b001),
+            (0x4030, 0xa000 | TABLE | PageTableFlags::NO_EXECUTE.bits()),
+            // 2 MiB pages, the second one is misaligned
+            (0x3028, 0x20_0000 | TABLE | PageTableFlags::HUGE_PAGE.bits()),
+            (0x3030, 0x20_2000 | TABLE | PageTableFlags::HUGE_PAGE.bits()),
+            // read-only 1 GiB page
+            (
+                0x2018,
+                0x4000_0000 | 0b101 | PageTableFlags::HUGE_PAGE.bits(),
+            ),
+        ] {
+            dram.write_u64(entry, value, LittleEndian).unwrap();
+        }
+        dram
+    }
+
+    #[test]
+    fn test_four_level_walk() {
+        let mut dram = tables();
+        let mut mmu = MMU::new(PagingMode::Long);
+        mmu.write_cr3(0x1000);
+        mmu.set_no_execute(true);
+        assert_eq!(
+            mmu.translate(&mut dram, 0x1234, AccessType::Read, PrivilegeLevel::User)
+                .unwrap(),
+            0x1234
+        );
+        mmu.set_enabled(true);
+
+        let mut translate =
+            |address, access, privilege| mmu.translate(&mut dram, address, access, privilege);
+        let supervisor = PrivilegeLevel::Supervisor;
+        let user = PrivilegeLevel::User;
+        let page = linear([1, 2, 3, 4], 0x123);
+        assert_eq!(translate(page, AccessType::Write, user).unwrap(), 0x8123);
+        let huge = linear([1, 2, 5, 0], 0x1234);
+        assert_eq!(translate(huge, AccessType::Read, user).unwrap(), 0x20_1234);
+        let giant = linear([1, 3, 7, 9], 0x678);
+        assert_eq!(
+            translate(giant, AccessType::Execute, user).unwrap(),
+            0x40e0_9678
+        );
+
+        let fault = |result: Result<u64, PagingError>| match result {
+            Err(PagingError::PageFault { error_code, .. }) => error_code,
+            result => panic!("expected a page fault, got {result:?}"),
+        };
+        use PageFaultErrorCode as E;
+        assert_eq!(
+            fault(translate(
+                linear([1, 2, 7, 0], 0),
+                AccessType::Read,
+                supervisor
+            )),
+            E::empty()
+        );
+        assert_eq!(
+            fault(translate(giant, AccessType::Write, user)),
+            E::PROTECTION_VIOLATION | E::WRITE | E::USER
+        );
+        assert_eq!(
+            fault(translate(linear([1, 2, 3, 5], 0), AccessType::Read, user)),
+            E::PROTECTION_VIOLATION | E::USER
+        );
+        assert_eq!(
+            fault(translate(
+                linear([1, 2, 3, 6], 0),
+                AccessType::Execute,
+                supervisor
+            )),
+            E::PROTECTION_VIOLATION | E::INSTRUCTION_FETCH
+        );
+        assert_eq!(
+            fault(translate(
+                linear([1, 2, 6, 0], 0),
+                AccessType::Read,
+                supervisor
+            )),
+            E::PROTECTION_VIOLATION | E::RESERVED_BIT
+        );
+        assert!(matches!(
+            translate(1 << 47, AccessType::Read, supervisor),
+            Err(PagingError::NonCanonical { .. })
+        ));
+
+        // supervisor writes to read-only pages only fault with CR0.WP set
+        mmu.set_write_protect(false);
+        assert_eq!(
+            mmu.translate(&mut dram, giant, AccessType::Write, supervisor)
+                .unwrap(),
+            0x40e0_9678
+        );
+
+        let entry = |address| {
+            PageTableFlags::from_bits_retain(dram.read_u64(address, LittleEndian).unwrap())
+        };
+        assert!(entry(0x1008).contains(PageTableFlags::ACCESSED));
+        assert!(entry(0x4020).contains(PageTableFlags::ACCESSED | PageTableFlags::DIRTY));
+        assert!(entry(0x3028).contains(PageTableFlags::ACCESSED));
+        assert!(!entry(0x3028).contains(PageTableFlags::DIRTY));
+        assert!(!entry(0x4028).contains(PageTableFlags::ACCESSED));
+    }
+
+    #[test]
+    fn test_five_level_walk() {
+        let mut dram = tables();
+        let mut mmu = MMU::new(PagingMode::LongLA57);
+        mmu.write_cr3(0x5000);
+        mmu.set_enabled(true);
+
+        let address = linear([1, 2, 3, 4], 0x123);
+        assert_eq!(
+            mmu.translate(&mut dram, address, AccessType::Read, PrivilegeLevel::User)
+                .unwrap(),
+            0x8123
+        );
+        // canonical with 57 bits, but the PML5 entry is not present
+        assert!(matches!(
+            mmu.translate(&mut dram, 1 << 48, AccessType::Read, PrivilegeLevel::User),
+            Err(PagingError::PageFault { .. })
+        ));
+        assert!(matches!(
+            mmu.translate(&mut dram, 1 << 56, AccessType::Read, PrivilegeLevel::User),
+            Err(PagingError::NonCanonical { .. })
+        ));
+    }
+
+    #[test]
+    fn test_legacy_walk() {
+        // page directory at 0x1000, page table at 0x2000
+        let mut dram = DRAM::new(0, 1 << 16);
+        dram.alloc(0, 1 << 16).unwrap();
+        for (entry, value) in [
+            (0x1004, 0x2000 | TABLE),
+            (0x2008, 0x7000 | 0b101),
+            // 4 MiB page at 0x1_0080_0000
+            (
+                0x1008,
+                0x0080_0000 | 1 << 13 | TABLE | PageTableFlags::HUGE_PAGE.bits(),
+            ),
+        ] {
+            dram.write_u32(entry, value as u32, LittleEndian).unwrap();
+        }
+
+        let mut mmu = MMU::new(PagingMode::Protected);
+        mmu.write_cr3(0x1000);
+        mmu.set_enabled(true);
+        let mut translate = |mmu: &MMU, address, access| {
+            mmu.translate(&mut dram, address, access, PrivilegeLevel::User)
+        };
+
+        let address = 1 << 22 | 2 << 12 | 0x345;
+        assert_eq!(translate(&mmu, address, AccessType::Read).unwrap(), 0x7345);
+        // the upper half of the address is ignored
+        assert_eq!(
+            translate(&mmu, 0xffff_ffff_0000_0000 | address, AccessType::Read).unwrap(),
+            0x7345
+        );
+        assert!(matches!(
+            translate(&mmu, address, AccessType::Write),
+            Err(PagingError::PageFault { .. })
+        ));
+
+        // without PSE the page size bit is ignored and the entry references a table
+        let large = 2 << 22 | 0x12_3456;
+        assert!(matches!(
+            translate(&mmu, large, AccessType::Read),
+            Err(PagingError::Memory(_))
+        ));
+        mmu.write_cr4(Cr4::PSE);
+        assert_eq!(
+            translate(&mmu, large, AccessType::Write).unwrap(),
+            0x1_0092_3456
+        );
+        assert_eq!(
+            dram.read_u32(0x1008, LittleEndian).unwrap() & 0x60,
+            (PageTableFlags::ACCESSED | PageTableFlags::DIRTY).bits() as u32
+        );
+    }
+
+    #[test]
+    fn test_pae_walk() {
+        // page directory pointers at 0x1020, page directory at 0x2000, page table at 0x3000
+        let mut dram = DRAM::new(0, 1 << 16);
+        dram.alloc(0, 1 << 16).unwrap();
+        for (entry, value) in [
+            (0x1028, 0x2000 | 1),
+            (0x1030, 0x2000 | 1 | PageTableFlags::WRITABLE.bits()),
+            (0x2000, 0x3000 | TABLE),
+            (0x3018, 0x9000 | TABLE | PageTableFlags::NO_EXECUTE.bits()),
+            (
+                0x2008,
+                0x0060_0000 | TABLE | PageTableFlags::HUGE_PAGE.bits(),
+            ),
+        ] {
+            dram.write_u64(entry, value, LittleEndian).unwrap();
+        }
+
+        let mut mmu = MMU::new(PagingMode::Protected);
+        mmu.write_cr3(0x1020);
+        mmu.write_cr4(Cr4::PAE);
+        mmu.set_enabled(true);
+        mmu.set_no_execute(true);
+        let mut translate =
+            |address, access| mmu.translate(&mut dram, address, access, PrivilegeLevel::User);
+
+        assert_eq!(
+            translate(1 << 30 | 3 << 12 | 0x10, AccessType::Write).unwrap(),
+            0x9010
+        );
+        assert!(matches!(
+            translate(1 << 30 | 3 << 12, AccessType::Execute),
+            Err(PagingError::PageFault { .. })
+        ));
+        assert_eq!(
+            translate(1 << 30 | 1 << 21 | 0x1_2345, AccessType::Read).unwrap(),
+            0x61_2345
+        );
+        // the writable bit is reserved in page directory pointers
+        assert!(matches!(
+            translate(2 << 30, AccessType::Read),
+            Err(PagingError::PageFault { error_code, .. })
+                if error_code.contains(PageFaultErrorCode::RESERVED_BIT)
+        ));
+        assert!(matches!(
+            translate(3 << 30, AccessType::Read),
+            Err(PagingError::PageFault { error_code, .. })
+                if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        ));
+    }
 }
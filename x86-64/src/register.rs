@@ -1,3 +1,4 @@
+use crate::instruction::{OperandSize, Segment};
 use crate::simd::*;
 use bitflags::bitflags;
 use paste::paste;
@@ -7,8 +8,10 @@ pub struct Registers {
     gr: [u64; 16],
     rip: u64,
     rflags: Flags,
-    cr0: CR0,
-    cr3: CR3,
+    /// The segment selectors, indexed by their encoding (ES, CS, SS, DS, FS, GS)
+    segments: [u16; 6],
+    fs_base: u64,
+    gs_base: u64,
     simd: [AVX512Register; 16],
 }
 
@@ -64,14 +67,21 @@ bitflags! {
     }
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Registers {
     pub fn new() -> Self {
         Self {
             gr: [0; 16],
             rip: 0,
             rflags: Flags::empty(),
-            cr0: CR0::empty(),
-            cr3: CR3::empty(),
+            segments: [0; 6],
+            fs_base: 0,
+            gs_base: 0,
             simd: [AVX512Register::new(); 16],
         }
     }
@@ -84,6 +94,71 @@ impl Registers {
         self.rip = value;
     }
 
+    /// Reads the selector of a segment register
+    pub fn segment(&self, segment: Segment) -> u16 {
+        self.segments[segment as usize]
+    }
+
+    pub fn write_segment(&mut self, segment: Segment, value: u16) {
+        self.segments[segment as usize] = value;
+    }
+
+    /// The base address added to FS-relative memory accesses
+    pub fn fs_base(&self) -> u64 {
+        self.fs_base
+    }
+
+    pub fn write_fs_base(&mut self, value: u64) {
+        self.fs_base = value;
+    }
+
+    /// The base address added to GS-relative memory accesses
+    pub fn gs_base(&self) -> u64 {
+        self.gs_base
+    }
+
+    pub fn write_gs_base(&mut self, value: u64) {
+        self.gs_base = value;
+    }
+
+    /// Reads the general purpose register with the given encoding index
+    pub fn gr(&self, index: u8) -> u64 {
+        self.gr[index as usize]
+    }
+
+    /// Writes the general purpose register with the given encoding index
+    pub fn write_gr(&mut self, index: u8, value: u64) {
+        self.gr[index as usize] = value;
+    }
+
+    /// Reads the low `size` bits of the general purpose register with the given encoding index
+    pub fn gr_sized(&self, index: u8, size: OperandSize) -> u64 {
+        self.gr[index as usize] & size.mask()
+    }
+
+    /// Writes the low `size` bits of the general purpose register with the given encoding index
+    ///
+    /// 32-bit writes zero-extend into the full register, 8 and 16-bit writes
+    /// preserve the upper bits.
+    pub fn write_gr_sized(&mut self, index: u8, size: OperandSize, value: u64) {
+        let reg = &mut self.gr[index as usize];
+        *reg = match size {
+            OperandSize::Qword | OperandSize::Dword => value & size.mask(),
+            OperandSize::Word | OperandSize::Byte => (*reg & !size.mask()) | (value & size.mask()),
+        };
+    }
+
+    /// Reads bits 8..16 of the general purpose register with the given index (AH to BH)
+    pub fn high_byte(&self, index: u8) -> u8 {
+        (self.gr[index as usize] >> 8) as u8
+    }
+
+    /// Writes bits 8..16 of the general purpose register with the given index (AH to BH)
+    pub fn write_high_byte(&mut self, index: u8, value: u8) {
+        let reg = &mut self.gr[index as usize];
+        *reg = (*reg & !0xff00) | ((value as u64) << 8);
+    }
+
     pub fn rflags(&self) -> &Flags {
         &self.rflags
     }
@@ -92,34 +167,39 @@ impl Registers {
         &mut self.rflags
     }
 
-    pub fn xmm(&self, index: usize) -> XmmView {
+    pub fn xmm(&self, index: usize) -> XmmView<'_> {
         self.simd[index].xmm()
     }
 
-    pub fn ymm(&self, index: usize) -> YmmView {
+    pub fn ymm(&self, index: usize) -> YmmView<'_> {
         self.simd[index].ymm()
     }
 
-    pub fn zmm(&self, index: usize) -> ZmmView {
+    pub fn zmm(&self, index: usize) -> ZmmView<'_> {
         self.simd[index].zmm()
     }
 
-    pub fn xmm_mut(&mut self, index: usize) -> XmmViewMut {
+    pub fn xmm_mut(&mut self, index: usize) -> XmmViewMut<'_> {
         self.simd[index].xmm_mut()
     }
 
-    pub fn ymm_mut(&mut self, index: usize) -> YmmViewMut {
+    pub fn ymm_mut(&mut self, index: usize) -> YmmViewMut<'_> {
         self.simd[index].ymm_mut()
     }
 
-    pub fn zmm_mut(&mut self, index: usize) -> ZmmViewMut {
+    pub fn zmm_mut(&mut self, index: usize) -> ZmmViewMut<'_> {
         self.simd[index].zmm_mut()
     }
 }
 
+/// Defines the register aliases of one width
+///
+/// `keep` is the mask of the bits of the 64-bit register preserved by a write.
+/// 32-bit writes zero-extend into the full register, while 8 and 16-bit writes
+/// only replace their low bits.
 macro_rules! define_rw {
     (
-        $($type:ty = {
+        $($type:ty, keep $keep:expr => {
             $($name:ident $index:expr)*
         })*
     ) => {
@@ -131,7 +211,7 @@ macro_rules! define_rw {
 
                 paste! {
                     pub fn [<write _ $name>](&mut self, value: $type) {
-                        self.gr[$index] = value as u64;
+                        self.gr[$index] = (self.gr[$index] & $keep) | value as u64;
                     }
                 }
             )*
@@ -141,7 +221,7 @@ macro_rules! define_rw {
 
 impl Registers {
     define_rw! {
-        u64 = {
+        u64, keep 0 => {
             rax 0
             rcx 1
             rdx 2
@@ -160,7 +240,7 @@ impl Registers {
             r15 15
         }
 
-        u32 = {
+        u32, keep 0 => {
             eax 0
             ecx 1
             edx 2
@@ -179,7 +259,7 @@ impl Registers {
             r15d 15
         }
 
-        u16 = {
+        u16, keep !0xffff => {
             ax 0
             cx 1
             dx 2
@@ -198,7 +278,7 @@ impl Registers {
             r15w 15
         }
 
-        u8 = {
+        u8, keep !0xff => {
             al 0
             cl 1
             dl 2
@@ -235,19 +315,19 @@ impl Registers {
     }
 
     pub fn write_ah(&mut self, value: u8) {
-        self.gr[0] = (self.gr[0] & 0xff) | ((value as u64) << 8);
+        self.gr[0] = (self.gr[0] & !0xff00) | ((value as u64) << 8);
     }
 
     pub fn write_ch(&mut self, value: u8) {
-        self.gr[1] = (self.gr[1] & 0xff) | ((value as u64) << 8);
+        self.gr[1] = (self.gr[1] & !0xff00) | ((value as u64) << 8);
     }
 
     pub fn write_dh(&mut self, value: u8) {
-        self.gr[2] = (self.gr[2] & 0xff) | ((value as u64) << 8);
+        self.gr[2] = (self.gr[2] & !0xff00) | ((value as u64) << 8);
     }
 
     pub fn write_bh(&mut self, value: u8) {
-        self.gr[3] = (self.gr[3] & 0xff) | ((value as u64) << 8);
+        self.gr[3] = (self.gr[3] & !0xff00) | ((value as u64) << 8);
     }
 }
 
@@ -255,6 +335,28 @@ impl Registers {
 mod test {
     use super::*;
 
+    const INITIAL: u64 = 0x1122_3344_5566_7788;
+
+    /// Checks that writing every alias of one width preserves or clears the expected bits
+    macro_rules! check_writes {
+        ($($name:ident $index:literal => $expected:literal)*) => {
+            paste! {
+                $(
+                    let mut reg = Registers::new();
+                    for i in 0..16 {
+                        reg.write_gr(i, INITIAL);
+                    }
+                    reg.[<write_ $name>](0xff as _);
+                    assert_eq!(reg.gr($index), $expected, stringify!($name));
+                    assert_eq!(reg.$name(), 0xff as _, stringify!($name));
+                    for i in (0..16).filter(|i| *i != $index) {
+                        assert_eq!(reg.gr(i), INITIAL, stringify!($name));
+                    }
+                )*
+            }
+        };
+    }
+
     #[test]
     fn test_rw() {
         let mut reg = Registers::new();
@@ -263,5 +365,65 @@ mod test {
         assert_eq!(reg.eax(), 0x90abcdef);
         assert_eq!(reg.ax(), 0xcdef);
         assert_eq!(reg.al(), 0xef);
+        assert_eq!(reg.ah(), 0xcd);
+    }
+
+    #[test]
+    fn test_partial_writes() {
+        check_writes! {
+            rax 0 => 0xff rcx 1 => 0xff rdx 2 => 0xff rbx 3 => 0xff
+            rsp 4 => 0xff rbp 5 => 0xff rsi 6 => 0xff rdi 7 => 0xff
+            r8 8 => 0xff r9 9 => 0xff r10 10 => 0xff r11 11 => 0xff
+            r12 12 => 0xff r13 13 => 0xff r14 14 => 0xff r15 15 => 0xff
+        }
+
+        check_writes! {
+            eax 0 => 0xff ecx 1 => 0xff edx 2 => 0xff ebx 3 => 0xff
+            esp 4 => 0xff ebp 5 => 0xff esi 6 => 0xff edi 7 => 0xff
+            r8d 8 => 0xff r9d 9 => 0xff r10d 10 => 0xff r11d 11 => 0xff
+            r12d 12 => 0xff r13d 13 => 0xff r14d 14 => 0xff r15d 15 => 0xff
+        }
+
+        check_writes! {
+            ax 0 => 0x1122_3344_5566_00ff cx 1 => 0x1122_3344_5566_00ff
+            dx 2 => 0x1122_3344_5566_00ff bx 3 => 0x1122_3344_5566_00ff
+            sp 4 => 0x1122_3344_5566_00ff bp 5 => 0x1122_3344_5566_00ff
+            si 6 => 0x1122_3344_5566_00ff di 7 => 0x1122_3344_5566_00ff
+            r8w 8 => 0x1122_3344_5566_00ff r9w 9 => 0x1122_3344_5566_00ff
+            r10w 10 => 0x1122_3344_5566_00ff r11w 11 => 0x1122_3344_5566_00ff
+            r12w 12 => 0x1122_3344_5566_00ff r13w 13 => 0x1122_3344_5566_00ff
+            r14w 14 => 0x1122_3344_5566_00ff r15w 15 => 0x1122_3344_5566_00ff
+        }
+
+        check_writes! {
+            al 0 => 0x1122_3344_5566_77ff cl 1 => 0x1122_3344_5566_77ff
+            dl 2 => 0x1122_3344_5566_77ff bl 3 => 0x1122_3344_5566_77ff
+            spl 4 => 0x1122_3344_5566_77ff bpl 5 => 0x1122_3344_5566_77ff
+            sil 6 => 0x1122_3344_5566_77ff dil 7 => 0x1122_3344_5566_77ff
+            r8b 8 => 0x1122_3344_5566_77ff r9b 9 => 0x1122_3344_5566_77ff
+            r10b 10 => 0x1122_3344_5566_77ff r11b 11 => 0x1122_3344_5566_77ff
+            r12b 12 => 0x1122_3344_5566_77ff r13b 13 => 0x1122_3344_5566_77ff
+            r14b 14 => 0x1122_3344_5566_77ff r15b 15 => 0x1122_3344_5566_77ff
+        }
+
+        check_writes! {
+            ah 0 => 0x1122_3344_5566_ff88 ch 1 => 0x1122_3344_5566_ff88
+            dh 2 => 0x1122_3344_5566_ff88 bh 3 => 0x1122_3344_5566_ff88
+        }
+    }
+
+    #[test]
+    fn test_sized_writes() {
+        let mut reg = Registers::new();
+        reg.write_gr(9, INITIAL);
+        reg.write_gr_sized(9, OperandSize::Byte, 0x1ff);
+        assert_eq!(reg.gr(9), 0x1122_3344_5566_77ff);
+        reg.write_gr_sized(9, OperandSize::Word, 0xabcd);
+        assert_eq!(reg.gr(9), 0x1122_3344_5566_abcd);
+        assert_eq!(reg.gr_sized(9, OperandSize::Byte), 0xcd);
+        reg.write_gr_sized(9, OperandSize::Dword, 0xffff_ffff_8000_0000);
+        assert_eq!(reg.gr(9), 0x8000_0000);
+        reg.write_gr_sized(9, OperandSize::Qword, INITIAL);
+        assert_eq!(reg.gr(9), INITIAL);
     }
 }
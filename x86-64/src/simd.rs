@@ -8,42 +8,48 @@ pub struct AVX512Register {
     data: [u8; 64],
 }
 
+impl Default for AVX512Register {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AVX512Register {
     pub fn new() -> Self {
         Self { data: [0; 64] }
     }
 
-    pub fn zmm_mut(&mut self) -> ZmmViewMut {
+    pub fn zmm_mut(&mut self) -> ZmmViewMut<'_> {
         ZmmViewMut {
             data: &mut self.data[..],
         }
     }
 
-    pub fn ymm_mut(&mut self) -> YmmViewMut {
+    pub fn ymm_mut(&mut self) -> YmmViewMut<'_> {
         YmmViewMut {
             data: &mut self.data[0..32],
         }
     }
 
-    pub fn xmm_mut(&mut self) -> XmmViewMut {
+    pub fn xmm_mut(&mut self) -> XmmViewMut<'_> {
         XmmViewMut {
             data: &mut self.data[0..16],
         }
     }
 
-    pub fn zmm(&self) -> ZmmView {
+    pub fn zmm(&self) -> ZmmView<'_> {
         ZmmView {
             data: &self.data[..],
         }
     }
 
-    pub fn ymm(&self) -> YmmView {
+    pub fn ymm(&self) -> YmmView<'_> {
         YmmView {
             data: &self.data[0..32],
         }
     }
 
-    pub fn xmm(&self) -> XmmView {
+    pub fn xmm(&self) -> XmmView<'_> {
         XmmView {
             data: &self.data[0..16],
         }
@@ -81,13 +87,13 @@ pub struct XmmViewMut<'a> {
 }
 
 impl ZmmView<'_> {
-    pub fn as_ymm(&self) -> YmmView {
+    pub fn as_ymm(&self) -> YmmView<'_> {
         YmmView {
             data: &self.data[0..32],
         }
     }
 
-    pub fn as_xmm(&self) -> XmmView {
+    pub fn as_xmm(&self) -> XmmView<'_> {
         XmmView {
             data: &self.data[0..16],
         }
@@ -95,7 +101,7 @@ impl ZmmView<'_> {
 }
 
 impl YmmView<'_> {
-    pub fn as_xmm(&self) -> XmmView {
+    pub fn as_xmm(&self) -> XmmView<'_> {
         XmmView {
             data: &self.data[0..16],
         }
@@ -120,9 +126,10 @@ macro_rules! read {
                     $(
                         $(
                             pub fn [<read_ $element_ty x $num_elements>](&self) -> [<$element_ty x $num_elements>] {
+                                const SIZE: usize = std::mem::size_of::<$element_ty>();
                                 let mut result = [$element_ty::zero(); $num_elements];
-                                for i in 0..$num_elements {
-                                    result[i] = self.data[i * std::mem::size_of::<$element_ty>()] as $element_ty;
+                                for (lane, bytes) in result.iter_mut().zip(self.data.chunks_exact(SIZE)) {
+                                    *lane = $element_ty::from_le_bytes(bytes.try_into().unwrap());
                                 }
                                 [<$element_ty x $num_elements>]::from_array(result)
                             }
@@ -152,15 +159,14 @@ macro_rules! write {
                     $(
                         $(
                             pub fn [<write_ $element_ty x $num_elements>](&mut self, value: [<$element_ty x $num_elements>]) {
-                                for i in 0..$num_elements {
-                                    self.data[i * std::mem::size_of::<$element_ty>()] = value[i] as u8;
+                                const SIZE: usize = std::mem::size_of::<$element_ty>();
+                                for (i, bytes) in self.data.chunks_exact_mut(SIZE).take($num_elements).enumerate() {
+                                    bytes.copy_from_slice(&value[i].to_le_bytes());
                                 }
                             }
 
                             pub fn [<write_ $element_ty x $num_elements _array>](&mut self, value: [$element_ty; $num_elements]) {
-                                for i in 0..$num_elements {
-                                    self.data[i * std::mem::size_of::<$element_ty>()] = value[i] as u8;
-                                }
+                                self.[<write_ $element_ty x $num_elements>]([<$element_ty x $num_elements>]::from_array(value));
                             }
                         )*
                     )*
@@ -294,7 +300,51 @@ read_write! {
 mod test {
     use super::*;
 
-    // TODO: Add tests
+    /// Writes distinct full-width values through every accessor of a view and
+    /// reads them back through both the mutable and the shared view
+    macro_rules! round_trip {
+        (
+            $($view:ident => $view_mut:ident = {
+                $(
+                    $element_ty:ty = {
+                        $(
+                            $num_elements:literal
+                        ),*
+                    }
+                )*
+            })*
+        ) => {
+            paste! {
+                $(
+                    $(
+                        $(
+                            let mut reg = AVX512Register::new();
+                            let value: [$element_ty; $num_elements] = std::array::from_fn(|i| {
+                                0x8123_4567_89ab_cdef_u64.wrapping_mul(i as u64 + 1) as $element_ty
+                            });
+                            let mut view = reg.$view_mut();
+                            view.[<write_ $element_ty x $num_elements _array>](value);
+                            assert_eq!(
+                                view.[<read_ $element_ty x $num_elements>](),
+                                [<$element_ty x $num_elements>]::from_array(value),
+                                stringify!($view_mut $element_ty $num_elements),
+                            );
+                            assert_eq!(
+                                reg.$view().[<read_ $element_ty x $num_elements>](),
+                                [<$element_ty x $num_elements>]::from_array(value),
+                                stringify!($view $element_ty $num_elements),
+                            );
+                            // nothing past the written lanes is touched
+                            let written = $num_elements * std::mem::size_of::<$element_ty>();
+                            let bytes = reg.zmm().read_u8x64();
+                            assert!((written..64).all(|i| bytes[i] == 0));
+                        )*
+                    )*
+                )*
+            }
+        };
+    }
+
     #[test]
     fn test() {
         let mut reg = AVX512Register::new();
@@ -305,4 +355,75 @@ mod test {
             i8x16::from_array([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, -1, -2, -3, -4, -5, -6])
         );
     }
+
+    #[test]
+    fn test_lane_layout() {
+        let mut reg = AVX512Register::new();
+        reg.xmm_mut()
+            .write_u32x4_array([0x0403_0201, 0x0807_0605, 0x0c0b_0a09, 0x100f_0e0d]);
+        assert_eq!(
+            reg.xmm().read_u8x16(),
+            u8x16::from_array(std::array::from_fn(|i| i as u8 + 1))
+        );
+        assert_eq!(
+            reg.xmm().read_u64x2(),
+            u64x2::from_array([0x0807_0605_0403_0201, 0x100f_0e0d_0c0b_0a09])
+        );
+
+        // floating-point lanes are bit-exact, including NaN payloads
+        let nan = f32::from_bits(0x7fa0_0001);
+        reg.ymm_mut()
+            .write_f32x8_array([1.5, -0.0, nan, f32::INFINITY, 0.0, 0.0, 0.0, 1e-40]);
+        let lanes = reg.ymm().read_u32x8();
+        assert_eq!(lanes[0], 1.5f32.to_bits());
+        assert_eq!(lanes[1], 0x8000_0000);
+        assert_eq!(lanes[2], 0x7fa0_0001);
+        assert_eq!(lanes[7], 1e-40f32.to_bits());
+        reg.zmm_mut().write_f64x8_array([-2.75; 8]);
+        assert_eq!(reg.zmm().read_i64x8()[7], (-2.75f64).to_bits() as i64);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip! {
+            xmm => xmm_mut = {
+                i8 = { 1, 2, 4, 8, 16 }
+                i16 = { 1, 2, 4, 8 }
+                i32 = { 1, 2, 4 }
+                i64 = { 1, 2 }
+                u8 = { 1, 2, 4, 8, 16 }
+                u16 = { 1, 2, 4, 8 }
+                u32 = { 1, 2, 4 }
+                u64 = { 1, 2 }
+                f32 = { 1, 2, 4 }
+                f64 = { 1, 2 }
+            }
+
+            ymm => ymm_mut = {
+                i8 = { 1, 2, 4, 8, 16, 32 }
+                i16 = { 1, 2, 4, 8, 16 }
+                i32 = { 1, 2, 4, 8 }
+                i64 = { 1, 2, 4 }
+                u8 = { 1, 2, 4, 8, 16, 32 }
+                u16 = { 1, 2, 4, 8, 16 }
+                u32 = { 1, 2, 4, 8 }
+                u64 = { 1, 2, 4 }
+                f32 = { 1, 2, 4, 8 }
+                f64 = { 1, 2, 4 }
+            }
+
+            zmm => zmm_mut = {
+                i8 = { 1, 2, 4, 8, 16, 32, 64 }
+                i16 = { 1, 2, 4, 8, 16, 32 }
+                i32 = { 1, 2, 4, 8, 16 }
+                i64 = { 1, 2, 4, 8 }
+                u8 = { 1, 2, 4, 8, 16, 32, 64 }
+                u16 = { 1, 2, 4, 8, 16, 32 }
+                u32 = { 1, 2, 4, 8, 16 }
+                u64 = { 1, 2, 4, 8 }
+                f32 = { 1, 2, 4, 8, 16 }
+                f64 = { 1, 2, 4, 8 }
+            }
+        }
+    }
 }